@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE `certfps` (
+                          `id` int(11) NOT NULL AUTO_INCREMENT,
+                          `id_user` int(11) NOT NULL,
+                          `fingerprint` char(64) NOT NULL DEFAULT '',
+                          PRIMARY KEY (`id`),
+                          UNIQUE KEY `fingerprint` (`fingerprint`),
+                          KEY `id_user` (`id_user`)
+) ENGINE=InnoDB AUTO_INCREMENT=1 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
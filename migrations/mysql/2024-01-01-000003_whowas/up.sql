@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE `whowas` (
+                          `id` int(11) NOT NULL AUTO_INCREMENT,
+                          `nick` char(11) NOT NULL DEFAULT '',
+                          `real_name` char(25) NOT NULL DEFAULT '',
+                          `ip` char(45) NOT NULL DEFAULT '',
+                          `signoff_time` bigint(11) NOT NULL,
+                          PRIMARY KEY (`id`),
+                          KEY `nick` (`nick`)
+) ENGINE=InnoDB AUTO_INCREMENT=1 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE `listeners` (
+                          `id` int(11) NOT NULL AUTO_INCREMENT,
+                          `address` char(255) NOT NULL DEFAULT '',
+                          `port` int(11) NOT NULL DEFAULT 0,
+                          `transport` char(4) NOT NULL DEFAULT 'tcp',
+                          `password` char(60) NOT NULL DEFAULT '',
+                          `oper_only` tinyint(1) NOT NULL DEFAULT 0,
+                          PRIMARY KEY (`id`)
+) ENGINE=InnoDB AUTO_INCREMENT=1 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
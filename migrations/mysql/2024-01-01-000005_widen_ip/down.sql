@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE `users` MODIFY `last_ip` char(11) NOT NULL DEFAULT '';
+ALTER TABLE `bans` MODIFY `content` char(20) NOT NULL;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+-- IPv6 addresses take up to 45 characters (e.g. `ffff:ffff:ffff:ffff:ffff:ffff:255.255.255.255`)
+ALTER TABLE `users` MODIFY `last_ip` char(45) NOT NULL DEFAULT '';
+ALTER TABLE `bans` MODIFY `content` char(45) NOT NULL;
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+
+CREATE TABLE `memberships` (
+                               `id` int(11) NOT NULL AUTO_INCREMENT,
+                               `id_user` int(11) NOT NULL,
+                               `id_channel` int(11) NOT NULL,
+                               PRIMARY KEY (`id`),
+                               KEY `user` (`id_user`),
+                               KEY `channel` (`id_channel`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
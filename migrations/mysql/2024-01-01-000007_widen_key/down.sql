@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE `settings` MODIFY `key` char(11) NOT NULL DEFAULT '';
@@ -0,0 +1,60 @@
+-- Your SQL goes here
+
+-- Nicks, channel names and setting keys are compared case insensitively, like with MySQL's `utf8mb4_general_ci`
+CREATE COLLATION IF NOT EXISTS nocase (provider = icu, locale = 'und-u-ks-level2', deterministic = false);
+
+CREATE TABLE bans (
+                      id SERIAL PRIMARY KEY,
+                      is_ip BOOLEAN NOT NULL,
+                      content VARCHAR(45) COLLATE nocase NOT NULL
+);
+
+CREATE TABLE channels (
+                          id SERIAL PRIMARY KEY,
+                          name VARCHAR(15) COLLATE nocase NOT NULL DEFAULT '',
+                          creation_time INTEGER NOT NULL,
+                          creator VARCHAR(11) COLLATE nocase NOT NULL DEFAULT '',
+                          topic TEXT NOT NULL,
+                          content TEXT NOT NULL
+);
+
+CREATE TABLE settings (
+                          id SERIAL PRIMARY KEY,
+                          key VARCHAR(11) COLLATE nocase NOT NULL DEFAULT '',
+                          content TEXT NOT NULL
+);
+
+CREATE TABLE users (
+                       id SERIAL PRIMARY KEY,
+                       last_login BIGINT NOT NULL,
+                       nick VARCHAR(11) COLLATE nocase NOT NULL DEFAULT '',
+                       real_name VARCHAR(25) NOT NULL DEFAULT '',
+                       last_ip VARCHAR(45) NOT NULL DEFAULT '',
+                       is_connected BOOLEAN NOT NULL,
+                       op BOOLEAN NOT NULL,
+                       thread_id INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE memberships (
+                             id SERIAL PRIMARY KEY,
+                             id_user INTEGER NOT NULL,
+                             id_channel INTEGER NOT NULL
+);
+
+CREATE INDEX memberships_user ON memberships (id_user);
+CREATE INDEX memberships_channel ON memberships (id_channel);
+
+INSERT INTO settings (key, content)
+VALUES
+    ('ip', '127.0.0.1'),
+    ('port', '6667'),
+    ('name', 'CompanyChat'),
+    ('motd', 'Bienvenue chez Company');
+
+INSERT INTO users (last_login, nick, real_name, last_ip, is_connected, op, thread_id)
+VALUES
+    (0, 'system', 'system', '127.0.0.1', false, true, -1);
+
+INSERT INTO channels (name, creation_time, creator, topic, content)
+VALUES
+    ('#general', 11, 'system', 'Salon général', ' ');
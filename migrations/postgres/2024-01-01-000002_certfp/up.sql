@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE certfps (
+                         id SERIAL PRIMARY KEY,
+                         id_user INTEGER NOT NULL,
+                         fingerprint VARCHAR(64) NOT NULL DEFAULT '' UNIQUE
+);
+
+CREATE INDEX certfps_user ON certfps (id_user);
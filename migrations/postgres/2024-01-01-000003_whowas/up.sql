@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE whowas (
+                        id SERIAL PRIMARY KEY,
+                        nick VARCHAR(11) COLLATE nocase NOT NULL DEFAULT '',
+                        real_name VARCHAR(25) NOT NULL DEFAULT '',
+                        ip VARCHAR(45) NOT NULL DEFAULT '',
+                        signoff_time BIGINT NOT NULL
+);
+
+CREATE INDEX whowas_nick ON whowas (nick);
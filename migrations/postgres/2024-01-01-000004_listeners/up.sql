@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE listeners (
+                           id SERIAL PRIMARY KEY,
+                           address VARCHAR(255) NOT NULL DEFAULT '',
+                           port INTEGER NOT NULL DEFAULT 0,
+                           transport VARCHAR(4) NOT NULL DEFAULT 'tcp',
+                           password VARCHAR(60) NOT NULL DEFAULT '',
+                           oper_only BOOLEAN NOT NULL DEFAULT false
+);
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+
+CREATE TABLE memberships (
+                             id SERIAL PRIMARY KEY,
+                             id_user INTEGER NOT NULL,
+                             id_channel INTEGER NOT NULL
+);
+
+CREATE INDEX memberships_user ON memberships (id_user);
+CREATE INDEX memberships_channel ON memberships (id_channel);
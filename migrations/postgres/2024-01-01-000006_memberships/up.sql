@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Channel memberships are live state, held in memory (see `rirc_state`)
+DROP TABLE memberships;
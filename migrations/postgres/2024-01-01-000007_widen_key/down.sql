@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE settings ALTER COLUMN key TYPE VARCHAR(11) COLLATE nocase;
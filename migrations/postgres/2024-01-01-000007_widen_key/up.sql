@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Setting keys are no longer limited to 11 characters (e.g. `clone_exempt`)
+ALTER TABLE settings ALTER COLUMN key TYPE VARCHAR(64) COLLATE nocase;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE bans;
+DROP TABLE settings;
+DROP TABLE memberships;
+DROP TABLE channels;
+DROP TABLE users;
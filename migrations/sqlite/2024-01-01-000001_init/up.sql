@@ -0,0 +1,58 @@
+-- Your SQL goes here
+
+-- Nicks, channel names and setting keys are compared case insensitively (`COLLATE NOCASE`), like with MySQL's `utf8mb4_general_ci`
+CREATE TABLE bans (
+                      id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                      is_ip BOOLEAN NOT NULL,
+                      content TEXT COLLATE NOCASE NOT NULL
+);
+
+CREATE TABLE channels (
+                          id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                          name TEXT COLLATE NOCASE NOT NULL DEFAULT '',
+                          creation_time INTEGER NOT NULL,
+                          creator TEXT COLLATE NOCASE NOT NULL DEFAULT '',
+                          topic TEXT NOT NULL,
+                          content TEXT NOT NULL
+);
+
+CREATE TABLE settings (
+                          id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                          key TEXT COLLATE NOCASE NOT NULL DEFAULT '',
+                          content TEXT NOT NULL
+);
+
+CREATE TABLE users (
+                       id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                       last_login BIGINT NOT NULL,
+                       nick TEXT COLLATE NOCASE NOT NULL DEFAULT '',
+                       real_name TEXT NOT NULL DEFAULT '',
+                       last_ip TEXT NOT NULL DEFAULT '',
+                       is_connected BOOLEAN NOT NULL,
+                       op BOOLEAN NOT NULL,
+                       thread_id INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE memberships (
+                             id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                             id_user INTEGER NOT NULL,
+                             id_channel INTEGER NOT NULL
+);
+
+CREATE INDEX memberships_user ON memberships (id_user);
+CREATE INDEX memberships_channel ON memberships (id_channel);
+
+INSERT INTO settings (key, content)
+VALUES
+    ('ip', '127.0.0.1'),
+    ('port', '6667'),
+    ('name', 'CompanyChat'),
+    ('motd', 'Bienvenue chez Company');
+
+INSERT INTO users (last_login, nick, real_name, last_ip, is_connected, op, thread_id)
+VALUES
+    (0, 'system', 'system', '127.0.0.1', 0, 1, -1);
+
+INSERT INTO channels (name, creation_time, creator, topic, content)
+VALUES
+    ('#general', 11, 'system', 'Salon général', ' ');
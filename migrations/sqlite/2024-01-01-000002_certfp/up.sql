@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE certfps (
+                         id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                         id_user INTEGER NOT NULL,
+                         fingerprint TEXT NOT NULL DEFAULT '' UNIQUE
+);
+
+CREATE INDEX certfps_user ON certfps (id_user);
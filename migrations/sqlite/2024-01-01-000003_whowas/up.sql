@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE whowas (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                        nick TEXT COLLATE NOCASE NOT NULL DEFAULT '',
+                        real_name TEXT NOT NULL DEFAULT '',
+                        ip TEXT NOT NULL DEFAULT '',
+                        signoff_time BIGINT NOT NULL
+);
+
+CREATE INDEX whowas_nick ON whowas (nick);
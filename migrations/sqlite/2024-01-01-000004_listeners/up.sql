@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE listeners (
+                           id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                           address TEXT NOT NULL DEFAULT '',
+                           port INTEGER NOT NULL DEFAULT 0,
+                           transport TEXT NOT NULL DEFAULT 'tcp',
+                           password TEXT NOT NULL DEFAULT '',
+                           oper_only BOOLEAN NOT NULL DEFAULT 0
+);
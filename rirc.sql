@@ -1,193 +0,0 @@
--- MariaDB dump 10.19  Distrib 10.10.3-MariaDB, for osx10.18 (arm64)
---
--- Host: localhost    Database: rirc
--- ------------------------------------------------------
--- Server version	10.10.2-MariaDB
-
-/*!40101 SET @OLD_CHARACTER_SET_CLIENT=@@CHARACTER_SET_CLIENT */;
-/*!40101 SET @OLD_CHARACTER_SET_RESULTS=@@CHARACTER_SET_RESULTS */;
-/*!40101 SET @OLD_COLLATION_CONNECTION=@@COLLATION_CONNECTION */;
-/*!40101 SET NAMES utf8mb4 */;
-/*!40103 SET @OLD_TIME_ZONE=@@TIME_ZONE */;
-/*!40103 SET TIME_ZONE='+00:00' */;
-/*!40014 SET @OLD_UNIQUE_CHECKS=@@UNIQUE_CHECKS, UNIQUE_CHECKS=0 */;
-/*!40014 SET @OLD_FOREIGN_KEY_CHECKS=@@FOREIGN_KEY_CHECKS, FOREIGN_KEY_CHECKS=0 */;
-/*!40101 SET @OLD_SQL_MODE=@@SQL_MODE, SQL_MODE='NO_AUTO_VALUE_ON_ZERO' */;
-/*!40111 SET @OLD_SQL_NOTES=@@SQL_NOTES, SQL_NOTES=0 */;
-
---
--- Table structure for table `__diesel_schema_migrations`
---
-
-DROP TABLE IF EXISTS `__diesel_schema_migrations`;
-/*!40101 SET @saved_cs_client     = @@character_set_client */;
-/*!40101 SET character_set_client = utf8 */;
-CREATE TABLE `__diesel_schema_migrations` (
-  `version` varchar(50) NOT NULL,
-  `run_on` timestamp NOT NULL DEFAULT current_timestamp(),
-  PRIMARY KEY (`version`)
-) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
-/*!40101 SET character_set_client = @saved_cs_client */;
-
---
--- Dumping data for table `__diesel_schema_migrations`
---
-
-LOCK TABLES `__diesel_schema_migrations` WRITE;
-/*!40000 ALTER TABLE `__diesel_schema_migrations` DISABLE KEYS */;
-/*!40000 ALTER TABLE `__diesel_schema_migrations` ENABLE KEYS */;
-UNLOCK TABLES;
-
---
--- Table structure for table `bans`
---
-
-DROP TABLE IF EXISTS `bans`;
-/*!40101 SET @saved_cs_client     = @@character_set_client */;
-/*!40101 SET character_set_client = utf8 */;
-CREATE TABLE `bans` (
-  `id` int(11) NOT NULL AUTO_INCREMENT,
-  `is_ip` tinyint(1) NOT NULL,
-  `content` char(20) NOT NULL,
-  PRIMARY KEY (`id`)
-) ENGINE=InnoDB AUTO_INCREMENT=4 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
-/*!40101 SET character_set_client = @saved_cs_client */;
-
---
--- Dumping data for table `bans`
---
-
-LOCK TABLES `bans` WRITE;
-/*!40000 ALTER TABLE `bans` DISABLE KEYS */;
-INSERT INTO `bans` VALUES
-(1,1,'1.1.1.1'),
-(2,0,'j0hndoe');
-/*!40000 ALTER TABLE `bans` ENABLE KEYS */;
-UNLOCK TABLES;
-
---
--- Table structure for table `channels`
---
-
-DROP TABLE IF EXISTS `channels`;
-/*!40101 SET @saved_cs_client     = @@character_set_client */;
-/*!40101 SET character_set_client = utf8 */;
-CREATE TABLE `channels` (
-  `id` int(11) NOT NULL AUTO_INCREMENT,
-  `name` char(15) NOT NULL DEFAULT '',
-  `creation_time` int(12) NOT NULL,
-  `creator` char(11) NOT NULL DEFAULT '',
-  `topic` mediumtext NOT NULL,
-  `content` longtext NOT NULL,
-  PRIMARY KEY (`id`)
-) ENGINE=InnoDB AUTO_INCREMENT=3 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
-/*!40101 SET character_set_client = @saved_cs_client */;
-
---
--- Dumping data for table `channels`
---
-
-LOCK TABLES `channels` WRITE;
-/*!40000 ALTER TABLE `channels` DISABLE KEYS */;
-INSERT INTO `channels` VALUES
-(1,'#informatique',11,'system','Bienvenue sur le salon du service informatique !\nCeci est le premier salon de ce serveur.',''),
-(2,'#general',11,'system','Salon général','');
-/*!40000 ALTER TABLE `channels` ENABLE KEYS */;
-UNLOCK TABLES;
-
---
--- Table structure for table `memberships`
---
-
-DROP TABLE IF EXISTS `memberships`;
-/*!40101 SET @saved_cs_client     = @@character_set_client */;
-/*!40101 SET character_set_client = utf8 */;
-CREATE TABLE `memberships` (
-  `id` int(11) NOT NULL AUTO_INCREMENT,
-  `id_user` int(11) NOT NULL,
-  `id_channel` int(11) NOT NULL,
-  PRIMARY KEY (`id`),
-  KEY `user` (`id_user`),
-  KEY `channel` (`id_channel`)
-) ENGINE=InnoDB AUTO_INCREMENT=59 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
-/*!40101 SET character_set_client = @saved_cs_client */;
-
---
--- Dumping data for table `memberships`
---
-
-LOCK TABLES `memberships` WRITE;
-/*!40000 ALTER TABLE `memberships` DISABLE KEYS */;
-/*!40000 ALTER TABLE `memberships` ENABLE KEYS */;
-UNLOCK TABLES;
-
---
--- Table structure for table `settings`
---
-
-DROP TABLE IF EXISTS `settings`;
-/*!40101 SET @saved_cs_client     = @@character_set_client */;
-/*!40101 SET character_set_client = utf8 */;
-CREATE TABLE `settings` (
-  `id` int(11) NOT NULL AUTO_INCREMENT,
-  `key` char(11) NOT NULL DEFAULT '',
-  `content` text NOT NULL,
-  PRIMARY KEY (`id`)
-) ENGINE=InnoDB AUTO_INCREMENT=5 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
-/*!40101 SET character_set_client = @saved_cs_client */;
-
---
--- Dumping data for table `settings`
---
-
-LOCK TABLES `settings` WRITE;
-/*!40000 ALTER TABLE `settings` DISABLE KEYS */;
-INSERT INTO `settings` VALUES
-(1,'ip','127.0.0.1'),
-(2,'port','6667'),
-(3,'name','CompanyChat'),
-(4,'motd','Bienvenue chez Company');
-/*!40000 ALTER TABLE `settings` ENABLE KEYS */;
-UNLOCK TABLES;
-
---
--- Table structure for table `users`
---
-
-DROP TABLE IF EXISTS `users`;
-/*!40101 SET @saved_cs_client     = @@character_set_client */;
-/*!40101 SET character_set_client = utf8 */;
-CREATE TABLE `users` (
-  `id` int(11) NOT NULL AUTO_INCREMENT,
-  `last_login` bigint(11) NOT NULL,
-  `nick` char(11) NOT NULL DEFAULT '',
-  `real_name` char(25) NOT NULL DEFAULT '',
-  `last_ip` char(11) NOT NULL DEFAULT '',
-  `is_connected` tinyint(1) NOT NULL,
-  `op` tinyint(1) NOT NULL,
-  `thread_id` int(11) NOT NULL DEFAULT 0,
-  PRIMARY KEY (`id`)
-) ENGINE=InnoDB AUTO_INCREMENT=40 DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
-/*!40101 SET character_set_client = @saved_cs_client */;
-
---
--- Dumping data for table `users`
---
-
-LOCK TABLES `users` WRITE;
-/*!40000 ALTER TABLE `users` DISABLE KEYS */;
-INSERT INTO `users` VALUES
-(1,0,'system','system','127.0.0.1',0,1,-1);
-/*!40000 ALTER TABLE `users` ENABLE KEYS */;
-UNLOCK TABLES;
-/*!40103 SET TIME_ZONE=@OLD_TIME_ZONE */;
-
-/*!40101 SET SQL_MODE=@OLD_SQL_MODE */;
-/*!40014 SET FOREIGN_KEY_CHECKS=@OLD_FOREIGN_KEY_CHECKS */;
-/*!40014 SET UNIQUE_CHECKS=@OLD_UNIQUE_CHECKS */;
-/*!40101 SET CHARACTER_SET_CLIENT=@OLD_CHARACTER_SET_CLIENT */;
-/*!40101 SET CHARACTER_SET_RESULTS=@OLD_CHARACTER_SET_RESULTS */;
-/*!40101 SET COLLATION_CONNECTION=@OLD_COLLATION_CONNECTION */;
-/*!40111 SET SQL_NOTES=@OLD_SQL_NOTES */;
-
--- Dump completed on 2023-02-20 21:12:18
@@ -3,41 +3,496 @@ mod rirc_schema;
 mod rirc_lib;
 mod rirc_protocol_handler;
 mod rirc_message_handler;
+mod rirc_limits;
+mod rirc_stream;
+mod rirc_storage;
+mod rirc_state;
+mod rirc_rehash;
+mod rirc_config;
+mod rirc_cli;
 
-use std::net::{SocketAddr, TcpListener};
-use std::thread::spawn;
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::{Arc, LazyLock};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+use clap::Parser;
 use dotenvy::dotenv;
-use log::{debug, info};
+use log::{debug, error, info, warn, LevelFilter};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
 use crate::rirc_lib::*;
-use crate::rirc_conn_handler::handler;
+use crate::rirc_cli::Cli;
+use crate::rirc_config::{load_config, log_level, set_config, set_config_path, set_log_level};
+use crate::rirc_conn_handler::{client_count, handler, shutdown};
+use crate::rirc_limits::{ConnectionLimits, ConnectionSlot};
+use crate::rirc_rehash::{load_tls, register, rehash};
+use crate::rirc_state::{flush, persist, state};
+use crate::rirc_storage::{db, establish_connection, run_migrations, set_database_url, Storage};
+use crate::rirc_stream::{ClientStream, TlsConfig};
 
-/// Main function, holds threads, database connection
+/// Every connection gets its own `thread_id`, whatever listener it came from (it's kept as a connection id, even though connections are tasks).
+static NEXT_THREAD_ID: AtomicI32 = AtomicI32::new(0);
+
+/// Main function, reads command line and configuration then runs listeners on the async runtime
 fn main() {
-    env_logger::init();
+    let cli = Cli::parse();
+
+    // Configuration file comes first, it may set log level and database (see `rirc_config`)
+    if let Some(path) = cli.config.clone() {
+        set_config_path(path, true);
+    }
+
+    match load_config() {
+        Ok(config) => set_config(Arc::new(config)),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            exit(1);
+        }
+    }
+
+    if cli.check_config {
+        println!("Configuration is valid");
+        return;
+    }
+
+    if let Some(level) = cli.log_level {
+        set_log_level(level);
+    }
+    if let Some(url) = cli.database_url.clone() {
+        set_database_url(url);
+    }
+
+    // Leaving the terminal has to happen before any thread is started
+    if let Some(path) = &cli.pid_file {
+        let started = if cli.foreground { write_pid_file(path) } else { daemonize(path) };
+
+        if let Err(error) = started {
+            eprintln!("{}", error);
+            exit(1);
+        }
+    }
+
+    // Log level from `--log-level`, `RUST_LOG`, or else from the configuration file (REHASH can change it)
+    match log_level() {
+        Some(level) => {
+            env_logger::Builder::new().filter_level(LevelFilter::Trace).init();
+            log::set_max_level(level);
+        }
+        Option::None => env_logger::init(),
+    }
     dotenv().ok();
 
+    if cli.migrate {
+        match run_migrations(&mut establish_connection()) {
+            Ok(applied) if applied.is_empty() => println!("No pending migration"),
+            Ok(applied) => println!("Applied migrations: {}", applied.join(", ")),
+            Err(error) => {
+                eprintln!("Could not apply migrations: {}", error);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    // Remembering when we started (sent to clients in 003)
+    LazyLock::force(&START_TIME);
+
     debug!("Connecting to database...");
-    let connection = &mut establish_connection();
-    clean_database(connection);
+    let mut pooled = establish_connection();
+    let connection = &mut *pooled;
+
+    // Schema is brought up to date before anything is read, a newer one is left alone
+    match run_migrations(connection) {
+        Ok(applied) => {
+            for version in applied {
+                info!("Applied migration {}", version);
+            }
+        }
+        Err(error) => {
+            error!("Could not apply migrations: {}", error);
+            exit(1);
+        }
+    }
+
+    connection.clean_database();
+
+    // Registered channels and bans are held in memory from now on (see `rirc_state`)
+    state().load(connection);
+
+    // Every TLS listener shares the same certificate, which REHASH can replace (see `rirc_rehash`)
+    let tls = load_tls(connection).unwrap_or_else(|error| panic!("{}", error)).map(TlsConfig::new);
+
+    // Listeners come from `--listen`, the `listeners` table (or `[[listeners]]`), or from settings
+    // Listeners given on the command line ask for the `password` setting, like the ones from settings
+    let password = password_setting(connection);
+    let mut servers: Vec<Server> = cli.listen.iter()
+        .flat_map(|(address, port)| Server::resolve(address, *port)
+            .unwrap_or_else(|error| panic!("Invalid address for --listen {}: {}", address, error)))
+        .map(|server| server.with_password(password.clone()))
+        .collect();
+    if servers.is_empty() {
+        servers = servers_from_listeners(connection, &tls);
+    }
+    if servers.is_empty() {
+        servers = servers_from_settings(connection, &tls);
+    }
+
+    if servers.is_empty() {
+        panic!("No listener configured, add `[[listeners]]` to the configuration file, rows to `listeners`, or set `ip` and `port` or `tls_port`.");
+    }
+
+    // Limits are shared by every connection
+    let limits = ConnectionLimits::from_settings(connection);
+
+    let tls_used = servers.iter().any(|server| server.tls.is_some());
+    register(limits.clone(), tls.filter(|_| tls_used));
+
+    // Database queries run on the blocking pool, with connections from the database pool (see `establish_connection()`)
+    let db_threads = connection.get_setting("db_threads")
+        .map(|setting| setting.content.trim().parse().unwrap_or(64))
+        .unwrap_or(64);
+
+    // Time given to clients to be disconnected at shutdown, then to pending writes to be saved, in seconds
+    let grace_time = Duration::from_secs(connection.get_setting("grace_time")
+        .map(|setting| setting.content.trim().parse().unwrap_or(10))
+        .unwrap_or(10));
+
+    // Connection goes back to the pool
+    drop(pooled);
+
+    let runtime = Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(db_threads)
+        .build()
+        .expect("Could not start runtime");
+
+    debug!("Starting connection managers...");
+    runtime.block_on(async {
+        let mut listeners: Vec<_> = servers.into_iter().map(|server| {
+            let limits = limits.clone();
+
+            tokio::spawn(listen(server, limits))
+        }).collect();
+
+        // Configuration is reloaded on SIGHUP, errors are logged (see `rehash()`)
+        tokio::spawn(async {
+            let mut hangup = signal(SignalKind::hangup()).expect("Could not handle SIGHUP");
+
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading configuration...");
+                // Errors in configuration are already logged by `rehash()`, the server keeps its current one
+                if let Err(error) = db(|connection| rehash(connection)).await {
+                    error!("Rehash failed: {}", error);
+                }
+            }
+        });
+
+        // Serving until every listener failed, or until we are asked to stop
+        tokio::select! {
+            _ = async { for listener in listeners.iter_mut() { listener.await.ok(); } } => {}
+            _ = stop_signal() => {}
+        }
+
+        // No new connection, every client is sent `ERROR :Server shutting down` and logged off (see `handler()`)
+        for listener in listeners {
+            listener.abort();
+        }
+        shutdown();
+
+        let deadline = Instant::now() + grace_time;
+        while client_count() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        if client_count() > 0 {
+            warn!("{} connections still open after grace period", client_count());
+        }
+    });
+
+    // Sessions left are marked disconnected, once history of the ones logged off is written
+    persist(|connection| connection.clean_database());
+    if ! flush(grace_time) {
+        warn!("Could not write every change to database before stopping");
+    }
+
+    runtime.shutdown_timeout(Duration::from_secs(1));
+
+    if let Some(path) = &cli.pid_file {
+        fs::remove_file(path).ok();
+    }
+
+    info!("Server stopped");
+}
+
+/// Function detaching the server from its terminal (it keeps running in a new session, standard input is closed),
+/// then writing its PID to `path`, it has to be called before any thread is started.
+fn daemonize(path: &PathBuf) -> Result<(), String> {
+    check_pid_file(path)?;
 
-    // This gets settings from database to create a `Server`.
-    let server = Server::from_settings(get_setting(connection, "ip").unwrap(), get_setting(connection, "port").unwrap());
+    // Parent returns to the shell, child carries on
+    match unsafe { libc::fork() } {
+        -1 => return Err("Could not fork: ".to_string() + io::Error::last_os_error().to_string().as_str()),
+        0 => {}
+        _ => exit(0),
+    }
+
+    unsafe { libc::setsid(); }
+
+    // Logs still go to standard error, it's up to the caller to redirect it
+    if let Ok(null) = fs::File::open("/dev/null") {
+        unsafe { libc::dup2(null.as_raw_fd(), 0); }
+    }
+
+    write_pid_file(path)
+}
+
+/// Function writing the server's PID to `path`, unless it's the PID file of a server still running.
+fn write_pid_file(path: &PathBuf) -> Result<(), String> {
+    check_pid_file(path)?;
+
+    fs::write(path, std::process::id().to_string() + "\n")
+        .map_err(|error| path.display().to_string() + ": " + error.to_string().as_str())
+}
+
+/// Function refusing to start if `path` holds the PID of a running process.
+fn check_pid_file(path: &PathBuf) -> Result<(), String> {
+    let pid = fs::read_to_string(path).ok().and_then(|pid| pid.trim().parse::<i32>().ok());
+
+    match pid {
+        Some(pid) if pid as u32 != std::process::id() && unsafe { libc::kill(pid, 0) } == 0 => {
+            Err(path.display().to_string() + ": server already running (PID " + pid.to_string().as_str() + ")")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Function waiting for SIGTERM (e.g. `kill $(pidof rustyrc)`) or SIGINT (Ctrl+C).
+async fn stop_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not handle SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Could not handle SIGINT");
+
+    tokio::select! {
+        _ = terminate.recv() => info!("SIGTERM received, shutting down..."),
+        _ = interrupt.recv() => info!("SIGINT received, shutting down..."),
+    }
+}
+
+/// Function creating a `Server` for each row of the `listeners` table,
+/// TLS listeners use `tls`, the certificate from `tls_cert` and `tls_key` settings.
+fn servers_from_listeners(connection: &mut dyn Storage, tls: &Option<TlsConfig>) -> Vec<Server> {
+    let mut servers: Vec<Server> = Vec::new();
+
+    for listener in connection.get_all_listeners() {
+        let password = Some(listener.password.trim().to_string()).filter(|password| ! password.is_empty());
+
+        let resolve = |port: i32| match u16::try_from(port).ok().filter(|port| *port != 0) {
+            Some(port) => Server::resolve(listener.address.as_str(), port)
+                .unwrap_or_else(|error| panic!("Invalid address for listener {}: {}", listener.id, error)),
+            Option::None => panic!("Invalid port for listener {}: {}", listener.id, port),
+        };
+
+        let transport_servers = match listener.transport.trim().to_lowercase().as_str() {
+            "tcp" => resolve(listener.port),
+            "tls" => {
+                let config = tls.clone()
+                    .unwrap_or_else(|| panic!("Listener {} uses TLS, set `tls_cert` and `tls_key`.", listener.id));
+
+                resolve(listener.port).into_iter().map(|server| server.with_tls(Some(config.clone()))).collect()
+            }
+            "unix" => vec![Server::unix(listener.address.trim())],
+            "ws" => resolve(listener.port).into_iter().map(|server| server.with_websocket(true)).collect(),
+            "wss" => {
+                let config = tls.clone()
+                    .unwrap_or_else(|| panic!("Listener {} uses TLS, set `tls_cert` and `tls_key`.", listener.id));
+
+                resolve(listener.port).into_iter().map(|server| server.with_tls(Some(config.clone())).with_websocket(true)).collect()
+            }
+            transport => panic!("Unknown transport for listener {}: {}", listener.id, transport),
+        };
+
+        servers.extend(transport_servers.into_iter()
+            .map(|server| server.with_password(password.clone()).with_oper_only(listener.oper_only)));
+    }
+
+    servers
+}
+
+/// Function returning the `password` setting (bcrypt hash clients must send with PASS), `None` if it's not set or empty.
+fn password_setting(connection: &mut dyn Storage) -> Option<String> {
+    connection.get_setting("password").ok()
+        .map(|setting| setting.content.trim().to_string())
+        .filter(|password| ! password.is_empty())
+}
+
+/// Function creating `Server`s from settings: a plain one on `ip` and `port` unless `port` is empty or 0,
+/// and a TLS one on `ip` and `tls_port` if `tls_port`, `tls_cert` and `tls_key` are set (`tls`).
+fn servers_from_settings(connection: &mut dyn Storage, tls: &Option<TlsConfig>) -> Vec<Server> {
+    let password = password_setting(connection);
+
+    let mut servers: Vec<Server> = Vec::new();
+
+    // Addresses are resolved once, a `Server` is created for each of them (see `Server::from_settings()`)
+    let ip = match connection.get_setting("ip") {
+        Ok(ip) => ip,
+        Err(_) => return servers,
+    };
+
+    if let Some(port) = connection.get_setting("port").ok().filter(|port| ! matches!(port.content.trim(), "" | "0")) {
+        match Server::from_settings(ip.clone(), port) {
+            Ok(plain) => servers.extend(plain.into_iter().map(|server| server.with_password(password.clone()))),
+            Err(error) => panic!("Invalid listener address: {}", error),
+        }
+    }
+
+    if let Ok(tls_port) = connection.get_setting("tls_port") {
+        if let Some(config) = tls.clone() {
+            match Server::from_settings(ip.clone(), tls_port) {
+                Ok(tls) => servers.extend(tls.into_iter().map(|server| server.with_password(password.clone()).with_tls(Some(config.clone())))),
+                Err(error) => panic!("Invalid listener address: {}", error),
+            }
+        }
+    }
+
+    servers
+}
+
+/// Function listening on `server`, spawning a `handler()` task for each incoming connection allowed by `limits`.
+async fn listen(server: Server, limits: ConnectionLimits) {
+    if let Some(path) = server.unix.clone() {
+        return listen_unix(server, path, limits).await;
+    }
 
     let socket = SocketAddr::new(server.addr, server.port);
 
-    info!("Starting listener on {}:{}", server.addr, server.port);
-    let listener = TcpListener::bind(socket).unwrap();
+    let transport = match (server.websocket, server.tls.is_some()) {
+        (false, false) => "plain",
+        (false, true) => "TLS",
+        (true, false) => "WebSocket",
+        (true, true) => "WebSocket TLS",
+    };
+
+    info!("Starting {} listener on {}", transport, socket);
+    let listener = match TcpListener::bind(socket).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Could not listen on {}: {}", socket, error);
+            return
+        }
+    };
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!("Could not accept connection: {}", error);
+                continue
+            }
+        };
 
-    debug!("Starting connection manager...");
-    // Spawning a thread of handler() for each incoming connection
-    for (thread_id, stream) in listener.incoming().enumerate() {
-        spawn(move || {
-            let connection = &mut establish_connection();
+        // IPv4 clients of a dual-stack (`::`) listener show as `::ffff:1.2.3.4`, they are handled as IPv4
+        let slot = limits.accept(addr.ip().to_canonical());
+        let server = server.clone();
 
-            let addr = stream.as_ref().unwrap().peer_addr().unwrap();
-            debug!("New connection from {}", addr);
+        // Handshakes happen in the connection's task, so slow clients don't hold the listener
+        tokio::spawn(async move {
+            let stream = match &server.tls {
+                Some(config) => match ClientStream::tls(stream, addr, config.current()).await {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        debug!("Could not start TLS with {}: {}", addr, error);
+                        return
+                    }
+                },
+                Option::None => ClientStream::plain(stream, addr),
+            };
 
-            handler(connection, stream.unwrap(), i32::try_from(thread_id).unwrap());
+            accept(server, slot, stream).await;
         });
     }
-}
\ No newline at end of file
+}
+
+/// Function listening on Unix domain socket `path` of `server`, like `listen()`,
+/// a socket left by a previous run is replaced.
+async fn listen_unix(server: Server, path: PathBuf, limits: ConnectionLimits) {
+    info!("Starting Unix listener on {}", path.display());
+
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(&path).ok();
+    }
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Could not listen on {}: {}", path.display(), error);
+            return
+        }
+    };
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => ClientStream::unix(stream),
+            Err(error) => {
+                warn!("Could not accept connection: {}", error);
+                continue
+            }
+        };
+
+        // Local clients share no IP, only the global limit applies to them
+        let slot = limits.accept_local();
+
+        tokio::spawn(accept(server.clone(), slot, stream));
+    }
+}
+
+/// Function running `handler()` for a connection to `server`, if `slot` was given by `ConnectionLimits`,
+/// WebSocket handshake happens here.
+async fn accept(server: Server, slot: Result<ConnectionSlot, &'static str>, stream: ClientStream) {
+    let addr = stream.addr;
+
+    let stream = if server.websocket {
+        let origins: Vec<String> = db(|connection| connection.get_setting("ws_origins")).await
+            .ok()
+            .and_then(|setting| setting.ok())
+            .map(|setting| setting.content.split(",").map(|origin| origin.trim().to_string()).filter(|origin| ! origin.is_empty()).collect())
+            .unwrap_or_default();
+
+        match stream.websocket(&origins).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                debug!("WebSocket handshake with {} failed: {}", addr, error);
+                return
+            }
+        }
+    } else {
+        stream
+    };
+
+    // Refused clients are told why, then connection is closed
+    let _slot = match slot {
+        Ok(slot) => slot,
+        Err(reason) => {
+            info!("Refusing connection from {}: {}", addr, reason);
+            let (_, mut writer) = stream.split();
+            writer.write_lines(("ERROR :Closing Link: ".to_string() + addr.ip().to_string().as_str() + " (" + reason + ")").as_str()).await.ok();
+            writer.close().await.ok();
+            return
+        }
+    };
+
+    let thread_id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+
+    debug!("New connection from {}", addr);
+
+    // Connection is accounted for until `handler()` returns and `_slot` is dropped
+    handler(stream, thread_id, server).await;
+}
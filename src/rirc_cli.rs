@@ -0,0 +1,85 @@
+//! # RustyRC CLI
+//!
+//! File containing the command line interface of the server binary.
+//!
+//! Flags override the configuration file and the environment (see `rirc_config`),
+//! so systemd units and containers don't need a `.env` in the working directory.
+
+use std::path::PathBuf;
+use clap::Parser;
+use log::LevelFilter;
+
+/// Basic IRC server, configuration comes from its file and from the `settings` table.
+#[derive(Parser)]
+#[command(name = "rustyrc", version, about)]
+pub struct Cli {
+    /// Configuration file (TOML), `rustyrc.toml` in the working directory is read if it exists
+    #[arg(short, long, value_name = "PATH", env = "RUSTYRC_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Database URL, used instead of `DATABASE_URL` and `url` in [storage]
+    #[arg(long, value_name = "URL")]
+    pub database_url: Option<String>,
+
+    /// Listen on ADDRESS:PORT (plain TCP) instead of configured listeners, can be repeated (e.g. `[::]:6667`)
+    #[arg(short, long, value_name = "ADDRESS:PORT", value_parser = parse_listen)]
+    pub listen: Vec<(String, u16)>,
+
+    /// Log level (off, error, warn, info, debug or trace), used instead of `RUST_LOG` and `level` in [logging]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Write the server's PID to PATH (removed once stopped), and run in the background unless `--foreground`
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Stay in the foreground, even with `--pid-file`
+    #[arg(short, long)]
+    pub foreground: bool,
+
+    /// Apply pending database migrations, then exit
+    #[arg(long)]
+    pub migrate: bool,
+
+    /// Check the configuration file, then exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+/// Parses `ADDRESS:PORT` given to `--listen`, IPv6 addresses are written in brackets (`[::1]:6667`).
+fn parse_listen(value: &str) -> Result<(String, u16), String> {
+    let (address, port) = value.rsplit_once(':')
+        .ok_or("expected ADDRESS:PORT, e.g. 127.0.0.1:6667".to_string())?;
+
+    let port = port.parse::<u16>().ok()
+        .filter(|port| *port != 0)
+        .ok_or(format!("invalid port `{}`", port))?;
+
+    if address.is_empty() {
+        return Err("missing address, e.g. 0.0.0.0:6667".to_string());
+    }
+
+    Ok((address.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_accepts_ipv4_ipv6_and_hostnames() {
+        assert_eq!(parse_listen("0.0.0.0:6667"), Ok(("0.0.0.0".to_string(), 6667)));
+        assert_eq!(parse_listen("[::1]:6697"), Ok(("[::1]".to_string(), 6697)));
+        assert_eq!(parse_listen("irc.example.com:6667"), Ok(("irc.example.com".to_string(), 6667)));
+    }
+
+    #[test]
+    fn parse_listen_refuses_missing_or_invalid_parts() {
+        assert!(parse_listen("6667").is_err());
+        assert!(parse_listen(":6667").is_err());
+        assert!(parse_listen("0.0.0.0:").is_err());
+        assert!(parse_listen("0.0.0.0:0").is_err());
+        assert!(parse_listen("0.0.0.0:65536").is_err());
+        assert!(parse_listen("0.0.0.0:irc").is_err());
+    }
+}
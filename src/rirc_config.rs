@@ -0,0 +1,445 @@
+//! # RustyRC Config
+//!
+//! File containing the configuration file (TOML), read at startup and by REHASH (see `rirc_rehash`).
+//!
+//! Its sections are `[server]` (identity), `[[listeners]]`, `[limits]`, `[[operators]]`, `[tls]`, `[logging]` and `[storage]`,
+//! every field is optional, see `rustyrc.example.toml`.
+//!
+//! Rows of the `settings` table override file values (see `Config::setting()`, used by `Storage::get_setting()`),
+//! and rows of the `listeners` table replace `[[listeners]]` when there is any.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, OnceLock, RwLock};
+use ipnet::IpNet;
+use log::LevelFilter;
+use serde::Deserialize;
+use crate::rirc_lib::Listener;
+use crate::rirc_stream::load_tls_config;
+
+/// Configuration file read when none is given.
+pub const DEFAULT_CONFIG_PATH: &str = "rustyrc.toml";
+
+/// Server identity.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub name: Option<String>, // network name (`name` setting)
+    pub motd: Option<String>, // message of the day (`motd` setting)
+    pub motd_file: Option<String>, // file holding the message of the day (`motd_file` setting)
+    pub password: Option<String>, // bcrypt hash of the password clients must send (`password` setting)
+    pub grace_time: Option<u64>, // seconds given to clients and pending writes at shutdown (`grace_time` setting)
+}
+
+/// A listener, like a row of the `listeners` table.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSection {
+    pub transport: String, // `tcp`, `tls`, `unix`, `ws` or `wss`
+    pub address: String, // comma separated addresses or hostnames, or socket path for `unix`
+    #[serde(default)]
+    pub port: u16, // unused for `unix`
+    #[serde(default)]
+    pub password: Option<String>, // bcrypt hash of the password clients must send
+    #[serde(default)]
+    pub oper_only: bool,
+}
+
+/// Limits applied to clients (settings of the same name).
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub reg_timeout: Option<u64>,
+    pub ping_idle: Option<u64>,
+    pub pong_wait: Option<u64>,
+    pub nick_delay: Option<u64>,
+    pub flood_delay: Option<u64>,
+    pub flood_burst: Option<u64>,
+    pub flood_max: Option<u64>,
+    pub max_clients: Option<u64>,
+    pub max_per_ip: Option<u64>,
+    pub reconn_max: Option<u64>,
+    pub reconn_time: Option<u64>,
+    pub clone_exempt: Vec<String>, // CIDR ranges
+    pub ws_origins: Vec<String>, // origins WebSocket clients may connect from
+}
+
+/// An operator, users become one by sending `OPER <name> <password>`.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OperatorSection {
+    pub name: String,
+    pub password: String, // bcrypt hash
+}
+
+/// Certificate of TLS listeners (`tls_cert`, `tls_key` and `tls_certfp` settings).
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: Option<String>, // PEM certificate chain
+    pub key: Option<String>, // PEM private key
+    pub certfp: bool, // ask clients for a certificate
+}
+
+/// Logging, `RUST_LOG` is used instead when set.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    pub level: Option<String>, // `off`, `error`, `warn`, `info`, `debug` or `trace`
+}
+
+/// Database, `DATABASE_URL`, `DATABASE_POOL_SIZE` and `DATABASE_TIMEOUT` are used instead when set.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    pub url: Option<String>,
+    pub pool_size: Option<u32>, // connections kept at most
+    pub timeout: Option<u64>, // seconds to wait for a free connection
+    pub threads: Option<u64>, // threads running queries (`db_threads` setting)
+}
+
+/// Content of the configuration file.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub listeners: Vec<ListenerSection>,
+    pub limits: LimitsSection,
+    pub operators: Vec<OperatorSection>,
+    pub tls: TlsSection,
+    pub logging: LoggingSection,
+    pub storage: StorageSection,
+}
+
+impl Config {
+    /// Public function reading configuration from file at `path`, returns every error found if it's not valid,
+    ///
+    /// A missing file is an empty configuration, unless `required`.
+    ///
+    /// Example:
+    /// ```rust
+    /// let config = Config::load(&PathBuf::from("/etc/rustyrc/rustyrc.toml"), true).unwrap();
+    /// ```
+    pub fn load(path: &PathBuf, required: bool) -> Result<Config, Vec<String>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound && ! required => return Ok(Config::default()),
+            Err(error) => return Err(vec![path.display().to_string() + ": " + error.to_string().as_str()]),
+        };
+
+        let config: Config = toml::from_str(content.as_str())
+            .map_err(|error| vec![path.display().to_string() + ": " + error.to_string().trim_end()])?;
+
+        let errors = config.validate();
+        if ! errors.is_empty() {
+            return Err(errors.into_iter().map(|error| path.display().to_string() + ": " + error.as_str()).collect());
+        }
+
+        Ok(config)
+    }
+
+    /// Returns every error of this configuration, as `section.field: reason`.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+
+        if self.server.name.as_ref().is_some_and(|name| name.trim().is_empty() || name.contains(' ')) {
+            errors.push("server.name: must be a single word".to_string());
+        }
+
+        if let Some(path) = &self.server.motd_file {
+            if let Err(error) = fs::metadata(path) {
+                errors.push("server.motd_file: ".to_string() + path.as_str() + ": " + error.to_string().as_str());
+            }
+        }
+
+        if self.server.password.as_ref().is_some_and(|password| ! is_bcrypt(password)) {
+            errors.push("server.password: must be a bcrypt hash (e.g. `htpasswd -bnBC 10 \"\" secret | tr -d ':\\n'`)".to_string());
+        }
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let field = |name: &str| "listeners[".to_string() + index.to_string().as_str() + "]." + name;
+
+            // Certificate of `tls` and `wss` listeners may come from `tls_cert` and `tls_key` settings
+            match listener.transport.as_str() {
+                "tcp" | "tls" | "unix" | "ws" | "wss" => {}
+                transport => errors.push(field("transport") + ": unknown transport `" + transport + "`, expected tcp, tls, unix, ws or wss"),
+            }
+
+            if listener.address.trim().is_empty() {
+                errors.push(field("address") + ": must not be empty");
+            }
+
+            if listener.transport != "unix" && listener.port == 0 {
+                errors.push(field("port") + ": must be between 1 and 65535");
+            }
+
+            if listener.password.as_ref().is_some_and(|password| ! is_bcrypt(password)) {
+                errors.push(field("password") + ": must be a bcrypt hash");
+            }
+        }
+
+        let limits = [
+            ("reg_timeout", self.limits.reg_timeout), ("ping_idle", self.limits.ping_idle), ("pong_wait", self.limits.pong_wait),
+            ("flood_delay", self.limits.flood_delay), ("max_clients", self.limits.max_clients), ("max_per_ip", self.limits.max_per_ip),
+            ("reconn_max", self.limits.reconn_max), ("reconn_time", self.limits.reconn_time),
+        ];
+        for (name, value) in limits {
+            if value == Some(0) {
+                errors.push("limits.".to_string() + name + ": must be at least 1");
+            }
+        }
+
+        for range in self.limits.clone_exempt.iter() {
+            if range.parse::<IpNet>().is_err() {
+                errors.push("limits.clone_exempt: invalid CIDR range `".to_string() + range.as_str() + "`, e.g. `10.0.0.0/8`");
+            }
+        }
+
+        for origin in self.limits.ws_origins.iter() {
+            if ! origin.starts_with("http://") && ! origin.starts_with("https://") {
+                errors.push("limits.ws_origins: invalid origin `".to_string() + origin.as_str() + "`, e.g. `https://chat.example.com`");
+            }
+        }
+
+        for (index, operator) in self.operators.iter().enumerate() {
+            let field = |name: &str| "operators[".to_string() + index.to_string().as_str() + "]." + name;
+
+            // OPER parameters are single words
+            if operator.name.is_empty() || operator.name.contains(char::is_whitespace) {
+                errors.push(field("name") + ": must be a single word");
+            }
+
+            if ! is_bcrypt(&operator.password) {
+                errors.push(field("password") + ": must be a bcrypt hash");
+            }
+        }
+
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => {
+                if let Err(error) = load_tls_config(cert, key, self.tls.certfp) {
+                    errors.push("tls: ".to_string() + error.as_str());
+                }
+            }
+            (Some(_), Option::None) => errors.push("tls.key: must be set along with `cert`".to_string()),
+            (Option::None, Some(_)) => errors.push("tls.cert: must be set along with `key`".to_string()),
+            (Option::None, Option::None) => {}
+        }
+
+        if self.logging.level.as_ref().is_some_and(|level| level.parse::<LevelFilter>().is_err()) {
+            errors.push("logging.level: expected off, error, warn, info, debug or trace".to_string());
+        }
+
+        if self.storage.url.as_ref().is_some_and(|url| url.trim().is_empty()) {
+            errors.push("storage.url: must not be empty".to_string());
+        }
+
+        if self.storage.pool_size == Some(0) {
+            errors.push("storage.pool_size: must be at least 1".to_string());
+        }
+
+        if self.storage.threads == Some(0) {
+            errors.push("storage.threads: must be at least 1".to_string());
+        }
+
+        errors
+    }
+
+    /// Returns value of setting `key` from the file, if set (see `Storage::get_setting()`),
+    ///
+    /// Example: `config().setting("flood_delay")` returns `Some("2000")` for `flood_delay = 2000` in `[limits]`.
+    pub fn setting(&self, key: &str) -> Option<String> {
+        let number = |value: Option<u64>| value.map(|value| value.to_string());
+        let list = |values: &Vec<String>| Some(values.join(",")).filter(|values| ! values.is_empty());
+
+        match key {
+            "name" => self.server.name.clone(),
+            "motd" => self.server.motd.clone(),
+            "motd_file" => self.server.motd_file.clone(),
+            "password" => self.server.password.clone(),
+            "grace_time" => number(self.server.grace_time),
+            "reg_timeout" => number(self.limits.reg_timeout),
+            "ping_idle" => number(self.limits.ping_idle),
+            "pong_wait" => number(self.limits.pong_wait),
+            "nick_delay" => number(self.limits.nick_delay),
+            "flood_delay" => number(self.limits.flood_delay),
+            "flood_burst" => number(self.limits.flood_burst),
+            "flood_max" => number(self.limits.flood_max),
+            "max_clients" => number(self.limits.max_clients),
+            "max_per_ip" => number(self.limits.max_per_ip),
+            "reconn_max" => number(self.limits.reconn_max),
+            "reconn_time" => number(self.limits.reconn_time),
+            "clone_exempt" => list(&self.limits.clone_exempt),
+            "ws_origins" => list(&self.limits.ws_origins),
+            "tls_cert" => self.tls.cert.clone(),
+            "tls_key" => self.tls.key.clone(),
+            "tls_certfp" => Some(if self.tls.certfp { "1" } else { "0" }.to_string()),
+            "db_threads" => number(self.storage.threads),
+            _ => Option::None,
+        }
+    }
+
+    /// Returns `[[listeners]]` as rows of the `listeners` table (see `Storage::get_all_listeners()`).
+    pub fn listeners(&self) -> Vec<Listener> {
+        self.listeners.iter().enumerate().map(|(index, listener)| Listener {
+            id: index as i32 + 1,
+            address: listener.address.clone(),
+            port: i32::from(listener.port),
+            transport: listener.transport.clone(),
+            password: listener.password.clone().unwrap_or_default(),
+            oper_only: listener.oper_only,
+        }).collect()
+    }
+}
+
+/// Returns `true` if `password` looks like a bcrypt hash.
+fn is_bcrypt(password: &str) -> bool {
+    password.starts_with("$2") && password.len() == 60
+}
+
+/// Path of the configuration file, and whether it has to exist, set once by `main()`.
+static CONFIG_PATH: OnceLock<(PathBuf, bool)> = OnceLock::new();
+
+/// Configuration in use, replaced by `set_config()`.
+static CONFIG: LazyLock<RwLock<Arc<Config>>> = LazyLock::new(|| RwLock::new(Arc::new(Config::default())));
+
+/// Public function setting path of the configuration file, it has to exist if `required` (given by the admin),
+/// `DEFAULT_CONFIG_PATH` is used (if it exists) when it's not called.
+pub fn set_config_path(path: PathBuf, required: bool) {
+    CONFIG_PATH.set((path, required)).ok();
+}
+
+/// Public function reading the configuration file (see `Config::load()`), without using it.
+pub fn load_config() -> Result<Config, Vec<String>> {
+    let (path, required) = CONFIG_PATH.get_or_init(|| (PathBuf::from(DEFAULT_CONFIG_PATH), false));
+
+    Config::load(path, *required)
+}
+
+/// Public function returning configuration in use,
+///
+/// Example:
+/// ```rust
+/// let level = config().logging.level.clone();
+/// ```
+pub fn config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Public function making `config` the configuration in use.
+pub fn set_config(config: Arc<Config>) {
+    *CONFIG.write().unwrap() = config;
+}
+
+/// Returns `true` if `password` is the one of operator `name` from `[[operators]]`,
+///
+/// Example: `is_operator("admin", "secret")`.
+pub fn is_operator(name: &str, password: &str) -> bool {
+    config().operators.iter()
+        .find(|operator| operator.name == name)
+        .is_some_and(|operator| bcrypt::verify(password, operator.password.as_str()).unwrap_or(false))
+}
+
+/// Log level given on the command line (`--log-level`), it wins over `RUST_LOG` and `[logging]`.
+static LOG_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+/// Public function making `level` the log level, whatever `RUST_LOG` or `[logging]` say.
+pub fn set_log_level(level: LevelFilter) {
+    LOG_LEVEL.set(level).ok();
+}
+
+/// Public function returning log level from `--log-level`, or else from `[logging]` (`error` by default, like `env_logger`),
+/// `None` if `RUST_LOG` is set (its filters are kept as they are).
+pub fn log_level() -> Option<LevelFilter> {
+    if let Some(level) = LOG_LEVEL.get() {
+        return Some(*level);
+    }
+
+    if std::env::var("RUST_LOG").is_ok() {
+        return Option::None;
+    }
+
+    Some(config().logging.level.as_ref().and_then(|level| level.parse().ok()).unwrap_or(LevelFilter::Error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(content: &str) -> Vec<String> {
+        toml::from_str::<Config>(content).unwrap().validate()
+    }
+
+    #[test]
+    fn validate_accepts_empty_and_complete_configurations() {
+        assert!(errors("").is_empty());
+        assert!(errors(r#"
+            [server]
+            name = "CompanyChat"
+            password = "$2b$04$8pl2M1OMAqIqiCKsKmAGLuh6lN0Pmuc6xYEv0RA.yTS6DJ6L8ctoq"
+
+            [[listeners]]
+            transport = "unix"
+            address = "/run/rustyrc/bots.sock"
+
+            [limits]
+            clone_exempt = ["127.0.0.0/8", "::1/128"]
+            ws_origins = ["https://chat.example.com"]
+
+            [[operators]]
+            name = "admin"
+            password = "$2b$04$8pl2M1OMAqIqiCKsKmAGLuh6lN0Pmuc6xYEv0RA.yTS6DJ6L8ctoq"
+
+            [logging]
+            level = "debug"
+        "#).is_empty());
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let errors = errors(r#"
+            [server]
+            name = "Company Chat"
+            password = "secret"
+
+            [[listeners]]
+            transport = "udp"
+            address = ""
+
+            [limits]
+            flood_delay = 0
+            clone_exempt = ["10.0.0.0/33"]
+            ws_origins = ["chat.example.com"]
+
+            [[operators]]
+            name = "the admin"
+            password = "secret"
+
+            [tls]
+            cert = "/etc/rustyrc/cert.pem"
+
+            [logging]
+            level = "loud"
+
+            [storage]
+            pool_size = 0
+        "#);
+
+        for field in [
+            "server.name", "server.password", "listeners[0].transport", "listeners[0].address", "listeners[0].port",
+            "limits.flood_delay", "limits.clone_exempt", "limits.ws_origins", "operators[0].name", "operators[0].password",
+            "tls.key", "logging.level", "storage.pool_size",
+        ] {
+            assert!(errors.iter().any(|error| error.starts_with(field)), "no error for {}", field);
+        }
+        assert_eq!(errors.len(), 13);
+    }
+
+    #[test]
+    fn setting_reads_file_values() {
+        let config: Config = toml::from_str("[limits]\nflood_delay = 500\nclone_exempt = [\"10.0.0.0/8\", \"::1/128\"]").unwrap();
+
+        assert_eq!(config.setting("flood_delay"), Some("500".to_string()));
+        assert_eq!(config.setting("clone_exempt"), Some("10.0.0.0/8,::1/128".to_string()));
+        assert_eq!(config.setting("motd"), Option::None);
+    }
+}
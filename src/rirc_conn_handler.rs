@@ -1,74 +1,416 @@
 //! # RustyRC Connection Handler
 //!
 //! File containing functions working on the connection itself.
+//!
+//! Each connection is a task reading lines (`handler()`), and a task writing lines (`writer()`),
+//! lines are sent to a connection by any task or thread through its outbox (see `send_to()`).
 
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
-use diesel::MysqlConnection;
-use log::trace;
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use log::{debug, error, trace};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Notify};
+use tokio::time::timeout;
 use crate::rirc_lib::*;
+use crate::rirc_limits::FloodControl;
 
 use crate::rirc_lib::IrcError::*;
 use crate::rirc_protocol_handler::*;
+use crate::rirc_storage::{db, Storage};
+use crate::rirc_stream::{ClientStream, LineWriter};
+
+/// Time given to `writer()` to send lines left once connection is closing.
+const CLOSING_TIMEOUT: Duration = Duration::from_secs(5);
 
-/// Public function that handles `TcpStream`,
-/// each lines sent to `handler` are sent to `rirc_protocol_handler::worker()`,
+/// Bytes of lines that can wait to be written to a connection, clients not reading fast enough are disconnected.
+const MAX_SENDQ: usize = 1024 * 1024;
+
+/// Public function that handles `ClientStream` (plain TCP, TLS, Unix domain socket or WebSocket),
+/// each lines sent to `handler` are sent to `rirc_protocol_handler::worker()` on the database pool (see `db()`),
 /// which will try to figure out how to answer to commands.
 ///
+/// Clients not registered after `reg_timeout` setting (in seconds, 60 by default) are disconnected,
+/// so are clients that did not send `server`'s password, or are not operators on an operators only `server`.
+///
+/// Lines are delayed, then client disconnected, when sent faster than allowed by `FloodControl` (operators are exempt).
+///
+/// Registered clients silent for `ping_idle` setting (120 seconds by default) are sent a PING,
+/// they are disconnected if they don't answer within `pong_wait` setting (60 seconds by default).
+///
+/// Every client is sent `ERROR :Server shutting down` then disconnected once `shutdown()` is called,
+/// clients with more than `MAX_SENDQ` bytes waiting to be written to them are disconnected for `SendQ exceeded`.
+///
+/// However connection ends, even if a request panicked, user is logged off (see `Cleanup`).
+///
 /// Example:
 /// ```rust
-/// let listener = TcpListener::bind(SocketAddr::new("127.0.0.1", 6667)).unwrap();
+/// let listener = TcpListener::bind(SocketAddr::new("127.0.0.1", 6667)).await.unwrap();
 ///
-/// for stream in listener.incoming() {
-///     handler(stream.unwrap())
+/// for thread_id in 0.. {
+///     let (stream, addr) = listener.accept().await.unwrap();
+///     tokio::spawn(handler(ClientStream::plain(stream, addr), thread_id, server.clone()));
 /// }
 /// ```
-pub fn handler(connection: &mut MysqlConnection, stream: TcpStream, thread_id: i32) {
-    let addr = stream.peer_addr().unwrap().ip();
+pub async fn handler(stream: ClientStream, thread_id: i32, server: Server) {
+    let addr = stream.addr.ip();
+    let mut session = Session::new(thread_id, addr.to_string(), &server);
+    session.certfp = stream.certfp.clone();
+
+    // Settings are read once per connection
+    let settings = db(|connection| {
+        let seconds = |connection: &mut dyn Storage, key: &str, default: u64| Duration::from_secs(connection.get_setting(key)
+            .map(|setting| setting.content.trim().parse().unwrap_or(default))
+            .unwrap_or(default));
+
+        (
+            // Time given to clients to register, in seconds
+            seconds(connection, "reg_timeout", 60),
+            // Time a registered client can stay silent before being sent a PING, in seconds
+            seconds(connection, "ping_idle", 120),
+            // Time a client is given to answer a PING, in seconds
+            seconds(connection, "pong_wait", 60),
+            FloodControl::from_settings(connection),
+        )
+    }).await;
+
+    let (registration_timeout, ping_idle, pong_wait, mut flood_control) = match settings {
+        Ok(settings) => settings,
+        Err(error) => {
+            error!("{}: could not read settings ({})", addr, error);
+            return
+        }
+    };
+
+    let certfp = stream.certfp.clone();
+    let (mut reader, writer) = stream.split();
+
+    // Lines are written by their own task, so they can be sent to this connection from anywhere (QUIT, NICK, PRIVMSG...)
+    let (outbox, inbox) = Outbox::new();
+    let mut writer = tokio::spawn(self::writer(writer, inbox, outbox.queued.clone(), addr.to_string()));
+    add_client(thread_id, Client { outbox: outbox.clone(), certfp });
+    let mut cleanup = Cleanup { thread_id, reason: "Connection lost".to_string() };
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut queue: VecDeque<(Instant, Request)> = VecDeque::new(); // lines read, with when they may be handled
+    let mut shutdown = SHUTDOWN.subscribe();
+
+    // Looping until connection has to be closed, for the reason given by `break`
+    let reason = loop {
+        let wait = if session.registered {
+            // Client talked since our PING, it's alive
+            if session.ping_sent.is_some_and(|ping_sent| session.last_activity > ping_sent) {
+                session.ping_sent = Option::None;
+            }
+
+            match session.ping_sent {
+                // Waiting for a PONG
+                Some(ping_sent) => match pong_wait.checked_sub(ping_sent.elapsed()) {
+                    Some(wait) if ! wait.is_zero() => wait,
+                    _ => {
+                        let res = Response::new("ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (Ping timeout)");
+                        sender(&outbox, res);
+                        break "Ping timeout".to_string()
+                    }
+                }
+                // Waiting for client to be idle long enough to deserve a PING
+                Option::None => match ping_idle.checked_sub(session.last_activity.elapsed()) {
+                    Some(wait) if ! wait.is_zero() => wait,
+                    _ => {
+                        sender(&outbox, Response::new("PING :localhost".to_string()));
+                        session.ping_sent = Some(Instant::now());
+                        continue
+                    }
+                }
+            }
+        } else {
+            // Unregistered clients only have what's left of registration timeout to send something
+            match registration_timeout.checked_sub(session.connected_at.elapsed()) {
+                Some(wait) if ! wait.is_zero() => wait,
+                _ => {
+                    let res = Response::new("ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (Registration timed out)");
+                    sender(&outbox, res);
+                    break "Registration timed out".to_string()
+                }
+            }
+        };
+
+        // Lines delayed by flood control are handled once due, lines are read meanwhile
+        let now = Instant::now();
+        if queue.front().is_none_or(|(due, _)| *due > now) {
+            let wait = queue.front().map_or(wait, |(due, _)| wait.min(*due - now));
+
+            // For every line sent to server,
+            // send request to worker()
+            let read = tokio::select! {
+                read = timeout(wait, reader.read_line(&mut buffer)) => read,
+                _ = shutdown.wait_for(|stopping| *stopping) => {
+                    sender(&outbox, Response::new("ERROR :Server shutting down".to_string()));
+                    break "Server shutting down".to_string()
+                }
+                _ = outbox.exceeded.notified() => {
+                    // Queue is full, this last line goes past the limit
+                    let line = "ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (SendQ exceeded)";
+                    outbox.queued.fetch_add(line.len(), Ordering::Relaxed);
+                    outbox.lines.send(Outgoing::Line(line)).ok();
+                    break "SendQ exceeded".to_string()
+                }
+            };
 
-    loop {
-        let reader = BufReader::new(stream.try_clone().unwrap());
+            match read {
+                // Timeout, whatever was read stays in `buffer`
+                Err(_) => continue,
+                // Connection closed by client
+                Ok(Ok(0)) => {
+                    debug!("{}: connection closed", addr);
+                    break "Connection closed".to_string()
+                }
+                Ok(Ok(_)) => { session.last_activity = Instant::now() }
+                Ok(Err(error)) if error.kind() == ErrorKind::InvalidData => {
+                    debug!("{}: {}", addr, error);
+                    sender(&outbox, Response::new("ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (" + error.to_string().as_str() + ")"));
+                    break error.to_string()
+                }
+                Ok(Err(error)) => {
+                    debug!("{}: connection lost ({})", addr, error);
+                    break "Connection lost".to_string()
+                }
+            }
+
+            // Lines are only handled once complete
+            if ! buffer.ends_with(b"\n") {
+                continue
+            }
+
+            let line = String::from_utf8_lossy(&buffer).trim_end_matches(['\r', '\n']).to_string();
+            buffer.clear();
+
+            if line.is_empty() {
+                continue
+            }
 
-        // For every line sent to server,
-        // send request to worker()
-        for line in reader.lines() {
-            let line = line.unwrap();
             trace!("{}: {}", addr, line.clone());
 
             let request = Request::new(line).unwrap();
 
-            match worker(connection, request, addr.to_string(), thread_id, stream.try_clone().unwrap()) {
-                Ok(res) => {
-                    // if request is QUIT
-                    if res.content == "BYE BYE" { return }
-
-                    sender(stream.try_clone().unwrap(), res);
+            // Flood control, checked when line arrives: operators are exempt, so is the first PONG answering our PING
+            let arrival = Instant::now();
+            let delay = if session.op || (request.command == Commands::PONG && session.ping_sent.take().is_some()) {
+                Duration::ZERO
+            } else {
+                match flood_control.check(arrival) {
+                    Ok(delay) => delay,
+                    Err(_) => {
+                        let res = Response::new("ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (Excess Flood)");
+                        sender(&outbox, res);
+                        break "Excess Flood".to_string()
+                    }
                 }
-                Err(error) => {
-                    // if error means user is banned, close connection
-                    if error == YoureBannedCreep { return }
+            };
+
+            queue.push_back((arrival + delay, request));
+            continue
+        }
+
+        let request = queue.pop_front().unwrap().1;
+
+        // `session` goes to the database pool with the request, and comes back (unless handling it panicked)
+        let handled = db(move |connection| {
+            let result = worker(connection, request, &mut session);
+            (result, session)
+        }).await;
+
+        let result = match handled {
+            Ok((result, returned)) => {
+                session = returned;
+                result
+            }
+            Err(error) => {
+                error!("{}: could not handle request ({})", addr, error);
+                let res = Response::new("ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (Internal error)");
+                sender(&outbox, res);
+                break "Internal error".to_string()
+            }
+        };
+
+        match result {
+            Ok(res) => {
+                let flow = res.flow.clone();
+
+                sender(&outbox, res);
+
+                // if request is QUIT
+                if flow == Flow::Close { break "Client Quit".to_string() }
+            }
+            Err(error) => {
+                // if error means user is banned, close connection
+                if error == YoureBannedCreep { break "Banned".to_string() }
+
+                // if password sent with PASS is wrong, or client is not an operator on an operators only server,
+                // tell client then close connection
+                let close = match error {
+                    PasswdMismatch => Some("Bad Password"),
+                    NoOperHost => Some("Operators only"),
+                    _ => Option::None,
+                };
+
+                let res = Response::from_error(error);
 
-                    let res = Response::from_error(error);
+                sender(&outbox, res);
 
-                    sender(stream.try_clone().unwrap(), res);
+                if let Some(reason) = close {
+                    let res = Response::new("ERROR :Closing Link: ".to_string() + addr.to_string().as_str() + " (" + reason + ")");
+                    sender(&outbox, res);
+                    break reason.to_string()
                 }
             }
         }
+    };
+
+    // However connection ended (QUIT, timeout, socket closed...), user is logged off,
+    // which also makes it leave its channels
+    cleanup.reason = reason;
+    drop(cleanup);
+
+    // Lines left are sent, then connection is closed, even if client doesn't read them
+    outbox.lines.send(Outgoing::Close).ok();
+    if timeout(CLOSING_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
+    }
+}
+
+/// Logs user of `thread_id` off (see `log_off()`) and makes its connection unreachable once dropped,
+/// so it's done even if `handler()` panics.
+struct Cleanup {
+    thread_id: i32,
+    reason: String, // quit reason sent to users sharing a channel
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        log_off(self.thread_id, self.reason.as_str());
+        remove_client(self.thread_id);
     }
 }
 
-/// Simple function `write`ing to `TcpStream`,
+/// What a connection's `writer()` is asked to do.
+pub enum Outgoing {
+    Line(String),
+    Close,
+}
+
+/// Lines waiting to be written to a connection by its `writer()`, no more than `MAX_SENDQ` bytes of them (see `sender()`).
+#[derive(Clone)]
+pub struct Outbox {
+    lines: UnboundedSender<Outgoing>,
+    queued: Arc<AtomicUsize>, // bytes of lines waiting, `writer()` counts them out once written
+    exceeded: Arc<Notify>, // notified when a line is refused, `handler()` then closes connection
+}
+
+impl Outbox {
+    /// Creates an empty `Outbox`, with the receiving end `writer()` reads lines from.
+    fn new() -> (Outbox, UnboundedReceiver<Outgoing>) {
+        let (lines, inbox) = unbounded_channel();
+
+        (Outbox { lines, queued: Arc::new(AtomicUsize::new(0)), exceeded: Arc::new(Notify::new()) }, inbox)
+    }
+}
+
+/// Connection registered by `add_client()`.
+pub struct Client {
+    pub outbox: Outbox,
+    pub certfp: Option<String>, // SHA-256 fingerprint of the TLS client certificate, if client sent one
+}
+
+/// Task writing lines from `inbox` to `writer`, until `Outgoing::Close` or a write error (connection closed),
+/// `queued` bytes are counted down as lines are written.
+async fn writer(mut writer: LineWriter, mut inbox: UnboundedReceiver<Outgoing>, queued: Arc<AtomicUsize>, addr: String) {
+    while let Some(outgoing) = inbox.recv().await {
+        let line = match outgoing {
+            Outgoing::Line(line) => line,
+            Outgoing::Close => break,
+        };
+
+        trace!("{}: {}", addr, line);
+        if let Err(error) = writer.write_lines(line.as_str()).await {
+            debug!("{}: could not write ({})", addr, error);
+            return
+        }
+
+        queued.fetch_sub(line.len(), Ordering::Relaxed);
+    }
+
+    writer.close().await.ok();
+}
+
+/// Set to `true` by `shutdown()`, every `handler()` is watching it.
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Public function asking every connection, current or new, to close (see `handler()`).
+pub fn shutdown() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// Outboxes of every connection, by `thread_id`, so lines can be sent to a given connection from any task or thread.
+static CLIENTS: LazyLock<Mutex<HashMap<i32, Client>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Locks `CLIENTS`, a task that panicked while holding it doesn't make it unusable for everyone else.
+fn clients() -> MutexGuard<'static, HashMap<i32, Client>> {
+    CLIENTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Makes connection of `thread_id` reachable by `send_to()`.
+pub fn add_client(thread_id: i32, client: Client) {
+    clients().insert(thread_id, client);
+}
+
+/// Makes connection of `thread_id` unreachable by `send_to()`, once it's closed.
+pub fn remove_client(thread_id: i32) {
+    clients().remove(&thread_id);
+}
+
+/// Returns how many connections are still open.
+pub fn client_count() -> usize {
+    clients().len()
+}
+
+/// Returns SHA-256 fingerprint of the TLS client certificate of connection of `thread_id`, if it sent one.
+pub fn client_certfp(thread_id: i32) -> Option<String> {
+    clients().get(&thread_id).and_then(|client| client.certfp.clone())
+}
+
+/// Sends `response` to connection of `thread_id`, if it's still open,
+///
+/// Example:
+/// ```rust
+/// send_to(12, Response::new(":johndoe!johndoe@1.2.3.4 QUIT :Quit: Bye".to_string()));
+/// ```
+pub fn send_to(thread_id: i32, response: Response) {
+    if let Some(client) = clients().get(&thread_id) {
+        sender(&client.outbox, response);
+    }
+}
+
+/// Simple function queuing `response` in `outbox`, to be written by the connection's `writer()`,
 ///
-/// - It is making sure that we send our responses with a \n at the end,
 /// - Will not send anything if `response.content` is empty,
-/// - Send a `trace!()` for every line sent.
-pub fn sender(mut stream: TcpStream, response: Response) {
+/// - Lines are sent with a \n at the end (or in their own frame for WebSocket clients),
+/// - Lines that would make more than `MAX_SENDQ` bytes wait are dropped, `handler()` then closes connection,
+/// - Closed connections are ignored, `handler()` cleans up once it notices.
+pub fn sender(outbox: &Outbox, response: Response) {
     let line = response.content;
 
     if line == "" {
         return
     }
 
-    trace!("{}: {}", stream.peer_addr().unwrap(), line);
-    stream.write((line + "\n").as_ref()).unwrap();
-}
\ No newline at end of file
+    if outbox.queued.fetch_add(line.len(), Ordering::Relaxed) + line.len() > MAX_SENDQ {
+        outbox.queued.fetch_sub(line.len(), Ordering::Relaxed);
+        outbox.exceeded.notify_one();
+        return
+    }
+
+    outbox.lines.send(Outgoing::Line(line)).ok();
+}
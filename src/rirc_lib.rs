@@ -2,41 +2,75 @@
 //!
 //! Shared file containing different structs and public functions for other modules to work.
 //!
-//! Including objects used for database communication.
+//! Including objects used for database communication (queries themselves are in `rirc_storage`).
 
-use std::env;
-use std::net::{IpAddr, Ipv4Addr};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use diesel::prelude::*;
-use diesel::mysql::MysqlConnection;
-use dotenvy::dotenv;
-use crate::rirc_lib::Error::*;
-use crate::rirc_lib::IrcError::*;
-use crate::rirc_schema::*;
+use log::warn;
+use crate::rirc_storage::Storage;
+use crate::rirc_stream::TlsConfig;
 
+/// Name and version sent to clients (002, 004).
+pub const SERVER_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"));
+
+/// Longest nickname accepted, bound to `users.nick` size.
+pub const NICKLEN: usize = 11;
+
+/// Longest channel name accepted, bound to `channels.name` size.
+pub const CHANNELLEN: usize = 15;
+
+/// Longest topic advertised to clients, so a 332 line stays under 512 bytes.
+pub const TOPICLEN: usize = 390;
+
+/// User modes known by the server (004): operator, connected through TLS.
+pub const USER_MODES: &str = "oZ";
+
+/// Channel modes known by the server (004), none for now.
+pub const CHANNEL_MODES: &str = "";
+
+/// Unix timestamp of server start, read at startup by `main()` so it's accurate in 003.
+pub static START_TIME: LazyLock<i64> = LazyLock::new(get_current_epoch);
+
+
+/// Holding what `handler()` should do with a connection once a `Response` is sent
+#[derive(Clone, PartialEq)]
+pub enum Flow {
+    Continue,
+    Close,
+}
 
 /// Holding responses sent by server in a struct
 #[derive(Clone)]
 pub struct Response {
     pub content: String,
+    pub flow: Flow,
 }
 
 impl Response {
     /// Create a `Response` from `String`.
     pub fn new(content: String) -> Response {
-        Response { content }
+        Response { content, flow: Flow::Continue }
+    }
+
+    /// Create a `Response` after which connection gets closed.
+    pub fn closing(content: String) -> Response {
+        Response { content, flow: Flow::Close }
     }
 
     /// Create a `Response` that will be interpreted as no response by `sender()`.
     pub fn no_response() -> Response {
-        Response { content: "".to_string() }
+        Response { content: "".to_string(), flow: Flow::Continue }
     }
 
     /// Create a `Response` from an `IrcError`.
     pub fn from_error(error: IrcError) -> Response {
         let line = error.to_u32().to_string() + " " + error.to_str();
 
-        Response { content: line }
+        Response { content: line, flow: Flow::Continue }
     }
 }
 
@@ -45,13 +79,13 @@ impl Response {
 #[allow(dead_code)]
 pub enum Commands {
     // Supported commands
-    CAP, JOIN, MOTD, NAMES, NICK, PART, PING, PONG, PRIVMSG, QUIT, USER, WHOIS, WHOWAS,
+    AUTHENTICATE, CAP, CERTFP, JOIN, MOTD, NAMES, NICK, OPER, PART, PASS, PING, PONG, PRIVMSG, QUIT, REHASH, USER, WHOIS, WHOWAS,
 
     SKIP,
 
     // Unsupported commands
     ADMIN, AWAY, CNOTE, CONNECT, DIE, ENCAP, ERROR, HELP, INFO, INVITE, ISON, KICK, KILL,
-    KNOCK, LINKS, LIST, LUSERS, MODE, NOTICE, OPER, PASS, REHASH, RULES, SERVER,
+    KNOCK, LINKS, LIST, LUSERS, MODE, NOTICE, RULES, SERVER,
     SERVICE, SERVLIST, SQUERY, SQUIT, SETNAME, SILENCE, STATS, SUMMON, TIME, TOPIC, TRACE,
     USERHOST, USERIP, USERS, VERSION, WALLOPS, WATCH, WHO,
 }
@@ -65,16 +99,21 @@ impl Commands {
     pub fn from_str(content: &str) -> Result<Commands, Error> {
         use self::Commands::*;
         match content {
+            "AUTHENTICATE" => Ok(AUTHENTICATE),
             "CAP" => Ok(CAP),
+            "CERTFP" => Ok(CERTFP),
             "JOIN" => Ok(JOIN),
             "MOTD" => Ok(MOTD),
             "NAMES" => Ok(NAMES),
             "NICK" => Ok(NICK),
+            "OPER" => Ok(OPER),
             "PART" => Ok(PART),
+            "PASS" => Ok(PASS),
             "PING" => Ok(PING),
             "PONG" => Ok(PONG),
             "PRIVMSG" => Ok(PRIVMSG),
             "QUIT" => Ok(QUIT),
+            "REHASH" => Ok(REHASH),
             "USER" => Ok(USER),
             "WHOIS" => Ok(WHOIS),
             "WHOWAS" => Ok(WHOWAS),
@@ -102,7 +141,8 @@ impl Request {
         let command = Commands::from_str(command_str)?;
 
         // Generate `content` from split, skip first part
-        let mut content = request_split.clone().nth(1).unwrap().to_string();
+        // (commands such as MOTD or QUIT can come without any argument)
+        let mut content = request_split.clone().nth(1).unwrap_or("").to_string();
         for i in request_split.skip(2) {
             content = content.to_owned() + " " + i
         }
@@ -114,6 +154,61 @@ impl Request {
     }
 }
 
+/// Holding the state of a client connection, from registration until it's closed,
+///
+/// A session is only registered once NICK and USER were received (and CAP negotiation, if any, ended),
+/// until then most commands are refused with ERR_NOTREGISTERED.
+pub struct Session {
+    pub thread_id: i32,
+    pub addr: String,
+    pub secure: bool, // connected through TLS
+    pub connected_at: Instant,
+    pub last_activity: Instant, // last time client sent something
+    pub ping_sent: Option<Instant>, // when a PING was sent that's still waiting for a PONG
+    pub listener_password: Option<String>, // bcrypt hash `password` has to match, from the `Server` client connected to
+    pub oper_only: bool, // only operators may register, from the `Server` client connected to
+    pub password: Option<String>,
+    pub nick: Option<String>,
+    pub last_nick_change: Option<Instant>,
+    pub real_name: Option<String>,
+    pub cap_negotiating: bool,
+    pub caps: Vec<String>, // capabilities acknowledged with CAP REQ
+    pub certfp: Option<String>, // SHA-256 fingerprint of the TLS client certificate, if client sent one
+    pub sasl_mechanism: Option<String>, // SASL mechanism chosen with AUTHENTICATE, until authentication ends
+    pub account: Option<String>, // nick of the account client logged into (SASL, or first `CERTFP ADD`)
+    pub created: Option<String>, // nick of the account this session created, it may attach a first fingerprint to it without login
+    pub registered: bool,
+    pub op: bool, // authenticated as an operator (OPER), operators are exempt from flood control
+}
+
+impl Session {
+    /// Create an unregistered `Session` for a client connecting from `addr` to `server`.
+    pub fn new(thread_id: i32, addr: String, server: &Server) -> Session {
+        Session {
+            thread_id,
+            addr,
+            secure: server.tls.is_some(),
+            connected_at: Instant::now(),
+            last_activity: Instant::now(),
+            ping_sent: Option::None,
+            listener_password: server.password.clone(),
+            oper_only: server.oper_only,
+            password: Option::None,
+            nick: Option::None,
+            last_nick_change: Option::None,
+            real_name: Option::None,
+            cap_negotiating: false,
+            caps: Vec::new(),
+            certfp: Option::None,
+            sasl_mechanism: Option::None,
+            account: Option::None,
+            created: Option::None,
+            registered: false,
+            op: false,
+        }
+    }
+}
+
 /// Enum holding general errors in the project
 #[derive(Debug)]
 pub enum Error {
@@ -134,12 +229,23 @@ pub enum IrcError {
     CannotSendToChan, // 404: ERR_CANNOTSENDTOCHAN
     TooManyChannels, // 405: ERR_TOOMANYCHANNELS
     TooManyTargets, // 407: ERR_TOOMANYTARGETS
+    NoRecipient, // 411: ERR_NORECIPIENT
+    NoTextToSend, // 412: ERR_NOTEXTTOSEND
+    NoMotd, // 422: ERR_NOMOTD
+    NoNicknameGiven, // 431: ERR_NONICKNAMEGIVEN
     ErroneusNickname, // 432: ERR_ERRONEUSNICKNAME
     NicknameInUse, // 433: ERR_NICKNAMEINUSE
+    NickTooFast, // 438: ERR_NICKTOOFAST
     NotOnChannel, // 442: ERR_NOTONCHANNEL
+    NotRegistered, // 451: ERR_NOTREGISTERED
     NeedMoreParams, // 461: ERR_NEEDMOREPARAMS
+    AlreadyRegistred, // 462: ERR_ALREADYREGISTRED
+    PasswdMismatch, // 464: ERR_PASSWDMISMATCH
     YoureBannedCreep, // 465: ERR_YOUREBANNEDCREEP
     YouWillBeBanned, // 466: ERR_YOUWILLBEBANNED
+    NoPrivileges, // 481: ERR_NOPRIVILEGES
+    NoOperHost, // 491: ERR_NOOPERHOST
+    NickLocked, // 902: ERR_NICKLOCKED (IRCv3)
 }
 
 impl IrcError {
@@ -157,12 +263,23 @@ impl IrcError {
             CannotSendToChan => 404,
             TooManyChannels => 405,
             TooManyTargets => 407,
+            NoRecipient => 411,
+            NoTextToSend => 412,
+            NoMotd => 422,
+            NoNicknameGiven => 431,
             ErroneusNickname => 432,
             NicknameInUse => 433,
+            NickTooFast => 438,
             NotOnChannel => 442,
+            NotRegistered => 451,
             NeedMoreParams => 461,
+            AlreadyRegistred => 462,
+            PasswdMismatch => 464,
             YoureBannedCreep => 465,
             YouWillBeBanned => 466,
+            NoPrivileges => 481,
+            NoOperHost => 491,
+            NickLocked => 902,
         }
     }
 
@@ -180,31 +297,27 @@ impl IrcError {
             CannotSendToChan => ":Cannot Send To Chan", // 404
             TooManyChannels => ":Too Many Channels", // 405
             TooManyTargets => ":Too Many Targets", // 407
+            NoRecipient => ":No Recipient Given", // 411
+            NoTextToSend => ":No Text To Send", // 412
+            NoMotd => ":MOTD File Is Missing", // 422
+            NoNicknameGiven => ":No Nickname Given", // 431
             ErroneusNickname => ":Erroneus Nickname", // 432
             NicknameInUse => ":Nickname In Use", // 433
+            NickTooFast => ":Nick Change Too Fast", // 438
             NotOnChannel => ":Not On Channel", // 442
+            NotRegistered => ":You Have Not Registered", // 451
             NeedMoreParams => ":Need More Params", // 461
+            AlreadyRegistred => ":You May Not Reregister", // 462
+            PasswdMismatch => ":Password Incorrect", // 464
             YoureBannedCreep => ":You're Banned, Creep", // 465
             YouWillBeBanned => ":You Will Be Banned", // 466
+            NoPrivileges => ":Permission Denied- You're not an IRC operator", // 481
+            NoOperHost => ":Only Operators May Connect Here", // 491
+            NickLocked => ":You Must Use A Nick Assigned To You", // 902
         }
     }
 }
 
-/// Public function that handles connecting to MySQL with Diesel using `DATABASE_URL`,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// let ip: Setting = get_setting(connection, "ip");
-/// ```
-pub fn establish_connection() -> MysqlConnection {
-    dotenv().ok();
-
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    MysqlConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
-}
-
 /// Queryable public struct linked to database using Diesel.
 #[derive(Queryable,Clone)]
 pub struct User {
@@ -218,204 +331,27 @@ pub struct User {
     pub thread_id: i32,
 }
 
-/// Insertable public struct linked to database using Diesel.
-#[derive(Insertable)]
-#[diesel(table_name = users)]
-pub struct NewUser<'a> {
-    pub last_login: &'a i64,
-    pub nick: &'a str,
-    pub real_name: &'a str,
-    pub last_ip: &'a str,
-    pub is_connected: &'a bool,
-    pub op: &'a bool,
-    pub thread_id: &'a i32,
-}
-
-/// Public function that will return a `User` when given its `nick`,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_user_from_nick(connection, "johndoe");
-/// ```
-pub fn get_user_from_nick<'a>(connection: &mut MysqlConnection, w_nick: &str) -> Result<User, Error> {
-    use crate::rirc_schema::users::dsl::*;
-
-    let mut user = users
-        .limit(1)
-        .filter(nick.eq(w_nick))
-        .load::<User>(connection)
-        .expect("Error loading users")
-        .into_iter();
-
-    if user.len() > 0 {
-        Ok(user.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-/// Public function that will return a `User` when given its `thread_id`,
+/// Queryable public struct linked to database using Diesel,
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_user_from_thread_id(connection, &24);
-/// ```
-pub fn get_user_from_thread_id<'a>(connection: &mut MysqlConnection,  w_thread_id: &i32) -> Result<User, Error> {
-    use crate::rirc_schema::users::dsl::*;
-
-    let mut user = users
-        .limit(1)
-        .filter(thread_id.eq(w_thread_id))
-        .load::<User>(connection)
-        .expect("Error loading users")
-        .into_iter();
-
-    if user.len() > 0 {
-        Ok(user.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-/// Public function that handles creating users,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// create_user(connection, "johndoe", "1.2.3.4", &true);
-/// ```
-pub fn create_user(connection: &mut MysqlConnection,
-                   w_last_login: &i64, w_nick: &str, w_real_name: &str,
-                   w_last_ip: &str, w_is_connected: &bool, w_op: &bool,
-                   w_thread_id: &i32) {
-
-    let new_user = NewUser {
-        last_login: w_last_login,
-        nick: w_nick,
-        real_name: w_real_name,
-        last_ip: w_last_ip,
-        is_connected: w_is_connected,
-        op: w_op,
-        thread_id: w_thread_id
-    };
-
-    diesel::insert_into(users::table)
-        .values(&new_user)
-        .execute(connection)
-        .expect("Error saving new user");
-}
-
-/// Public function that handles editing certain parts of an existing user from its username,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// edit_user(connection, &1674587646,"johndoe", "1.1.1.1", &true, &4);
-/// ```
-pub fn edit_user(connection: &mut MysqlConnection,
-                 w_last_login: &i64, w_nick: &str, w_last_ip: &str,
-                 w_is_connected: &bool, w_thread_id: &i32) -> Result<(), Error> {
-    use crate::rirc_schema::users::dsl::*;
-    use crate::rirc_schema::users;
-
-    if get_user_from_nick(connection, w_nick).is_err() {
-        return Err(NoResultInDatabase);
-    }
-
-    diesel::update(users::table)
-        .filter(nick.eq(w_nick))
-        .set(last_login.eq(w_last_login))
-        .execute(connection)
-        .expect("Error editing user");
-
-    diesel::update(users::table)
-        .filter(nick.eq(w_nick))
-        .set(last_ip.eq(w_last_ip))
-        .execute(connection)
-        .expect("Error editing user");
-
-    diesel::update(users::table)
-        .filter(nick.eq(w_nick))
-        .set(is_connected.eq(w_is_connected))
-        .execute(connection)
-        .expect("Error editing user");
-
-    diesel::update(users::table)
-        .filter(nick.eq(w_nick))
-        .set(thread_id.eq(w_thread_id))
-        .execute(connection)
-        .expect("Error editing user");
-
-    Ok(())
-}
-
-/// Public function that sets `is_connected` to `w_is_connected` when given a certain `User`.
-pub fn set_connected(connection: &mut MysqlConnection,
-                     user: User, w_is_connected: &bool) {
-    use crate::rirc_schema::users::dsl::*;
-    use crate::rirc_schema::users;
-
-    diesel::update(users::table)
-        .filter(id.eq(user.id))
-        .set(is_connected.eq(w_is_connected))
-        .execute(connection)
-        .expect("Error editing user");
-
-    // if we want to declare our user as logged off
-    if ! w_is_connected {
-        diesel::update(users::table)
-            .filter(thread_id.eq(user.thread_id))
-            .set(thread_id.eq(-1))
-            .execute(connection)
-            .expect("Error editing user");
-    }
-}
-
-/// Public function that sets `real_name` to `w_real_name` from `User`,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// set_real_name(connection, "johndoe", "John Doe");
-/// ```
-pub fn set_real_name(connection: &mut MysqlConnection,
-                     user: User, w_real_name: &str) {
-    use crate::rirc_schema::users::dsl::*;
-    use crate::rirc_schema::users;
-
-    diesel::update(users::table)
-        .filter(id.eq(user.id))
-        .set(real_name.eq(w_real_name))
-        .execute(connection)
-        .expect("Error editing user");
+/// Each row is a snapshot of a `User` taken when they signed off (QUIT, nick change or disconnect).
+#[derive(Queryable,Clone)]
+pub struct Whowas {
+    pub id: i32,
+    pub nick: String,
+    pub real_name: String,
+    pub ip: String,
+    pub signoff_time: i64,
 }
 
-/// Public function that cleans database, it will set all users to logged off and set all threads id to -1
+/// Queryable public struct linked to database using Diesel,
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// clean_database(connection);
-/// ```
-pub fn clean_database(connection: &mut MysqlConnection) {
-    use crate::rirc_schema::users::dsl::*;
-    use crate::rirc_schema::users;
-
-    diesel::update(users::table)
-        .set(is_connected.eq(false))
-        .execute(connection)
-        .expect("Error editing user");
-
-    diesel::update(users::table)
-        .set(thread_id.eq(-1))
-        .execute(connection)
-        .expect("Error editing user");
-
-    diesel::delete(memberships::table)
-        .execute(connection)
-        .expect("Error removing memberships");
+/// Each row is a TLS client certificate fingerprint (SHA-256, lowercase hex) attached to a `User`,
+/// which can then log in with SASL EXTERNAL.
+#[derive(Queryable,Clone)]
+pub struct Certfp {
+    pub id: i32,
+    pub id_user: i32,
+    pub fingerprint: String,
 }
 
 /// Function used when manipulating timestamps (for channels and users),
@@ -425,6 +361,15 @@ pub fn get_current_epoch() -> i64 {
     i64::try_from(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()).unwrap()
 }
 
+/// Turns a unix timestamp (as stored in database) into a human readable UTC date.
+///
+/// Example: `format_epoch(1674587646)` returns `"2023-01-24T19:14:06Z"`.
+pub fn format_epoch(epoch: i64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(u64::try_from(epoch).unwrap_or(0));
+
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
 /// Queryable private struct linked to database using Diesel.
 #[derive(Queryable)]
 pub struct Ban {
@@ -433,59 +378,6 @@ pub struct Ban {
     pub content: String,
 }
 
-/// Insertable private struct linked to database using Diesel.
-#[derive(Insertable)]
-#[diesel(table_name = bans)]
-struct NewBan<'a> {
-    pub is_ip: &'a bool,
-    pub content: &'a str,
-}
-
-/// Public function that will return a `Ban` when given `is_ip` and `content`,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_ban(connection, &true, "1.1.1.1");
-/// get_ban(connection, &false, "johndoe");
-/// ```
-pub fn get_ban<'a>(connection: &mut MysqlConnection, w_is_ip: &bool, w_name: &str) -> Result<Ban,Error> {
-    use crate::rirc_schema::bans::dsl::*;
-
-    let mut ban = bans
-        .limit(1)
-        .filter(is_ip.eq(w_is_ip))
-        .filter(content.eq(w_name))
-        .load::<Ban>(connection)
-        .expect("Error loading bans")
-        .into_iter();
-
-    if ban.len() > 0 {
-        Ok(ban.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-/// Public function that handles creating bans,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// create_ban(connection, &true, "1.2.3.4"); // does IP ban
-/// create_ban(connection, &false, "johndoe"); // does name-based ban
-/// ```
-pub fn create_ban(connection: &mut MysqlConnection, is_ip: &bool, content: &str) {
-    use crate::rirc_schema::bans;
-
-    let new_ban = NewBan { is_ip, content };
-
-    diesel::insert_into(bans::table)
-        .values(&new_ban)
-        .execute(connection)
-        .expect("Error saving new ban");
-}
-
 /// Queryable public struct linked to database using Diesel.
 #[derive(Queryable,Clone)]
 pub struct Channel {
@@ -498,367 +390,302 @@ pub struct Channel {
 }
 
 
-/// Insertable private struct linked to database using Diesel.
-#[derive(Insertable)]
-#[diesel(table_name = channels)]
-pub struct NewChannel<'a> {
-    pub name: &'a str,
-    pub creation_time: &'a i32,
-    pub creator: &'a str,
-    pub topic: &'a str,
-    pub content: &'a str,
-}
-
-/// Public function that will return a `Channel` when given it's `name`
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_channel(connection, "name");
-/// ```
-pub fn get_channel<'a>(connection: &mut MysqlConnection, w_name: &str) -> Result<Channel, Error> {
-    use crate::rirc_schema::channels::dsl::*;
-
-    let mut channel = channels
-        .limit(1)
-        .filter(name.eq(w_name))
-        .load::<Channel>(connection)
-        .expect("Error loading channels")
-        .into_iter();
-
-    if channel.len() == 1 {
-        Ok(channel.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
+/// Queryable public struct linked to database using Diesel.
+#[derive(Queryable,Clone)]
+pub struct Setting {
+    pub id: i32,
+    pub key: String,
+    pub content: String,
 }
 
-/// Public function that will return a `Channel` when given it's `id`
+/// Public function returning the message of the day, if any,
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_channel_from_id(connection, &2);
-/// ```
-pub fn get_channel_from_id<'a>(connection: &mut MysqlConnection, w_id: &i32) -> Result<Channel, Error> {
-    use crate::rirc_schema::channels::dsl::*;
-
-    let mut channel = channels
-        .limit(1)
-        .filter(id.eq(w_id))
-        .load::<Channel>(connection)
-        .expect("Error loading channels")
-        .into_iter();
-
-    if channel.len() == 1 {
-        Ok(channel.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-// Public function that will return all `Channel`s,
+/// Content of the file at `motd_file` setting is preferred so admins can edit it without SQL,
+/// `motd` setting is used when no file is set or when it can't be read.
 ///
 /// Example:
 /// ```rust
 /// let connection = &mut establish_connection();
-/// get_all_channels(connection);
+/// get_motd(connection);
 /// ```
-pub fn get_all_channels<'a>(connection: &mut MysqlConnection) -> Result<Vec<Channel>, Error> {
-    use crate::rirc_schema::channels::dsl::*;
-
-    let channel = channels
-        .load::<Channel>(connection)
-        .expect("Error loading users");
+pub fn get_motd(connection: &mut dyn Storage) -> Option<String> {
+    let mut motd = "".to_string();
 
-    if channel.len() > 0 {
-        Ok(channel)
-    } else {
-        Err(NoResultInDatabase)
+    if let Ok(path) = connection.get_setting("motd_file") {
+        match fs::read_to_string(path.content.trim()) {
+            Ok(content) => { motd = content }
+            Err(error) => { warn!("Could not read MOTD file {}: {}", path.content.trim(), error) }
+        }
     }
-}
-
-/// Public function that handles creating channels,
-///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// create_channel(connection, "world", 1673616716, "system", "Welcome to our cool channel #world", "system: Hello, World");
-/// ```
-pub fn create_channel(connection: &mut MysqlConnection, name: &str, creation_time: &i32, creator: &str, topic: &str, content: &str) {
-    use crate::rirc_schema::channels;
-
-    let new_channel = NewChannel { name, creation_time, creator, content, topic };
-
-    diesel::insert_into(channels::table)
-        .values(&new_channel)
-        .execute(connection)
-        .expect("Error saving new channel");
-}
-
-/// Function used to add message when user sends PRIVMSG command
-pub fn add_message(connection: &mut MysqlConnection, channel: Channel, w_content: &str) -> Result<(), IrcError> {
-    use crate::rirc_schema::channels::dsl::*;
-    use crate::rirc_schema::channels;
 
-    let line = w_content;
-        //(nick.to_string() + " " + w_content);
-
-    diesel::update(channels::table)
-        .filter(id.eq(channel.id))
-        .set(content.eq(line))
-        .execute(connection)
-        .expect("Error editing channel");
-
-    Ok(())
-}
-
-/// Function used to send in every channel a user is in
-pub fn broadcast_as_user(connection: &mut MysqlConnection, nick: &str, w_content: String) -> Result<(), IrcError> {
-    let user = get_user_from_nick(connection, nick).unwrap();
-    let memberships = get_all_user_memberships(connection, user.id).unwrap();
-
-    for membership in memberships {
-        let channel = get_channel_from_id(connection, &membership.id_channel).unwrap();
-
-        // cant turn the line into a fucking variable because this language hates me
-        add_message(connection, channel.clone(), w_content.clone().replace("[channel]", channel.name.as_str()).as_str()).unwrap();
+    if motd.trim().is_empty() {
+        if let Ok(setting) = connection.get_setting("motd") {
+            motd = setting.content;
+        }
     }
 
-    Ok(())
+    Some(motd).filter(|motd| ! motd.trim().is_empty())
 }
 
-/// Queryable public struct linked to database using Diesel.
-#[derive(Queryable)]
-pub struct Setting {
-    pub id: i32,
-    pub key: String,
-    pub content: String,
-}
-
-/// Public function that will return a `Setting` when given it's `key`
+/// Queryable public struct linked to database using Diesel,
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_setting(connection, "name");
-/// ```
-pub fn get_setting<'a>(connection: &mut MysqlConnection, w_key: &str) -> Result<Setting, Error> {
-    use crate::rirc_schema::settings::dsl::*;
-
-    let mut setting = settings
-        .limit(1)
-        .filter(key.eq(w_key))
-        .load::<Setting>(connection)
-        .expect("Error loading settings")
-        .into_iter();
-
-    if setting.len() > 0 {
-        Ok(setting.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-/// Queryable public struct linked to database using Diesel.
-#[derive(Queryable)]
-pub struct Membership {
+/// Each row is a listener clients can connect to:
+/// - `transport` is `tcp`, `tls`, `unix`, `ws` (WebSocket) or `wss` (WebSocket over TLS),
+/// - `address` is a comma separated list of addresses or hostnames, or the socket path for `unix`,
+/// - `password` is a bcrypt hash clients must send with PASS, none if empty,
+/// - only operators may register through listeners with `oper_only`.
+#[derive(Queryable,Clone)]
+pub struct Listener {
     pub id: i32,
-    pub id_user: i32,
-    pub id_channel: i32,
+    pub address: String,
+    pub port: i32,
+    pub transport: String,
+    pub password: String,
+    pub oper_only: bool,
 }
 
-
-/// Insertable private struct linked to database using Diesel.
-#[derive(Insertable)]
-#[diesel(table_name = memberships)]
-pub struct NewMembership<'a> {
-    pub id_user: &'a i32,
-    pub id_channel: &'a i32,
+/// Returns only the first word of the given `str`.
+pub fn first_word(content: &str) -> &str {
+    content.split_whitespace().next().unwrap_or(&*content)
 }
 
-/// Public function used to return the latest channel membership created,
+/// Returns `content` up to its first CR, LF or NUL, so text relayed to others can't carry extra lines.
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_last_membership(connection);
-/// ```
-pub fn get_last_membership<'a>(connection: &mut MysqlConnection) -> Result<Membership, Error> {
-    use crate::rirc_schema::memberships::dsl::*;
-
-    let mut membership = memberships
-        .limit(1)
-        .order(id.desc())
-        .load::<Membership>(connection)
-        .expect("Error loading memberships")
-        .into_iter();
-
-    if membership.len() > 0 {
-        Ok(membership.nth(0).unwrap())
-    } else {
-        Err(NoResultInDatabase)
-    }
+/// Example: `first_line("bye\r\nKILL x")` returns `"bye"`.
+pub fn first_line(content: &str) -> &str {
+    content.split(['\r', '\n', '\0']).next().unwrap_or(content)
 }
 
-/// Public function used to return all memberships linked to a certain user_id,
+/// Splits `content` into lines of at most `width` characters,
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_all_user_memberships(connection, 12);
-/// ```
-pub fn get_all_user_memberships(connection: &mut MysqlConnection, w_id: i32) -> Result<Vec<Membership>, Error> {
-    use crate::rirc_schema::memberships::dsl::*;
-
-    let membership = memberships
-        .filter(id_user.eq(w_id))
-        .load::<Membership>(connection)
-        .expect("Error loading memberships");
-
-    if membership.len() > 0 {
-        Ok(membership)
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-/// Public function used to return all memberships linked to a certain channel_id,
+/// Lines are broken on whitespace when possible, words longer than `width` are cut,
+/// empty lines of `content` are kept.
 ///
-/// Example:
-/// ```rust
-/// let connection = &mut establish_connection();
-/// get_all_channel_memberships(connection, 12);
-/// ```
-pub fn get_all_channel_memberships(connection: &mut MysqlConnection, w_id: i32) -> Result<Vec<Membership>, Error> {
-    use crate::rirc_schema::memberships::dsl::*;
-
-    let membership = memberships
-        .filter(id_channel.eq(w_id))
-        .load::<Membership>(connection)
-        .expect("Error loading memberships");
-
-    if membership.len() > 0 {
-        Ok(membership)
-    } else {
-        Err(NoResultInDatabase)
-    }
-}
-
-/// Public function used to create memberships
-pub fn create_membership(connection: &mut MysqlConnection, user: User, channel: Channel) {
-    use crate::rirc_schema::memberships;
-
-    let new_membership = NewMembership {
-        id_user: &user.thread_id,
-        id_channel: &channel.id,
-    };
-
-    diesel::insert_into(memberships::table)
-        .values(&new_membership)
-        .execute(connection)
-        .expect("Error saving new membership");
-}
+/// Example: `wrap_text("Hello World", 5)` returns `["Hello", "World"]`.
+pub fn wrap_text(content: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    for raw_line in content.lines() {
+        let mut line = "".to_string();
+
+        for word in raw_line.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+
+            // word has to be cut
+            while word.len() > width {
+                if ! line.is_empty() {
+                    lines.push(line);
+                }
+                line = word.drain(..width).collect();
+            }
 
-/// Function deleting all memberships linked to a certain `User`.
-pub fn delete_user_membership(connection: &mut MysqlConnection, user: User) {
-    use crate::rirc_schema::memberships;
-    use crate::rirc_schema::memberships::dsl::*;
+            let word: String = word.into_iter().collect();
 
-    diesel::delete(memberships::table)
-        .filter(id_user.eq(user.id))
-        .execute(connection)
-        .expect("Error removing memberships");
-}
-
-/// Function deleting a certain membership from it's database id.
-pub fn delete_membership(connection: &mut MysqlConnection, w_id: i32) {
-    use crate::rirc_schema::memberships;
-    use crate::rirc_schema::memberships::dsl::*;
+            if line.is_empty() {
+                line = word;
+            } else if line.chars().count() + 1 + word.chars().count() <= width {
+                line = line + " " + word.as_str();
+            } else {
+                lines.push(line);
+                line = word;
+            }
+        }
 
-    diesel::delete(memberships::table)
-        .filter(id.eq(w_id))
-        .execute(connection)
-        .expect("Error removing memberships");
-}
+        lines.push(line);
+    }
 
-/// Returns only the first word of the given `str`.
-pub fn first_word(content: &str) -> &str {
-    content.split_whitespace().next().unwrap_or(&*content)
+    lines
 }
 
 /// Public struct used to hold IP and port to listen to,
 ///
 /// Example:
 /// ```rust
-/// let server = Server::new("127.0.0.1", 6667);
-/// let socket = SocketAddr::new(server.get_addr(), server.get_port());
+/// let server = Server::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 6667);
+/// let socket = SocketAddr::new(server.addr, server.port);
 /// ```
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Server {
     pub addr: IpAddr,
     pub port: u16,
+    pub password: Option<String>, // bcrypt hash of the password clients must send with PASS, if any
+    pub tls: Option<TlsConfig>, // clients have to connect through TLS if set
+    pub unix: Option<PathBuf>, // listening on this Unix domain socket instead of `addr` and `port` if set
+    pub websocket: bool, // clients connect through WebSocket (over TLS if `tls` is set)
+    pub oper_only: bool, // only operators may register
 }
 
 #[allow(dead_code)]
 impl Server {
-    /// Turns a `&str` and `u16` into a `Server`.
+    /// Turns an `IpAddr` and `u16` into a `Server`.
     ///
-    /// Example: `Server::new("127.0.0.1", 6667);`.
-    pub fn new(addr: &str, port: u16) -> Server {
+    /// Example: `Server::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6667);`.
+    pub fn new(addr: IpAddr, port: u16) -> Server {
         return Server {
-            addr: Server::parse_addr(addr),
+            addr,
             port,
+            password: Option::None,
+            tls: Option::None,
+            unix: Option::None,
+            websocket: false,
+            oper_only: false,
         };
     }
 
-    /// Public function creating a Server from two Settings
+    /// Creates a `Server` listening on Unix domain socket `path`, its clients are seen as connecting from 127.0.0.1.
+    ///
+    /// Example: `Server::unix("/run/rustyrc/bots.sock");`.
+    pub fn unix(path: &str) -> Server {
+        let mut server = Server::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        server.unix = Some(PathBuf::from(path));
+
+        server
+    }
+
+    /// Makes clients of this `Server` connect through TLS using `config`.
+    ///
+    /// Example: `Server::new(addr, 6697).with_tls(load_tls_config("cert.pem", "key.pem", false).ok().map(TlsConfig::new));`.
+    pub fn with_tls(mut self, config: Option<TlsConfig>) -> Server {
+        self.tls = config;
+
+        self
+    }
+
+    /// Makes clients of this `Server` send a password matching the bcrypt `password` hash before registering.
+    ///
+    /// Example: `Server::new(addr, 6667).with_password(Some("$2y$10$...".to_string()));`.
+    pub fn with_password(mut self, password: Option<String>) -> Server {
+        self.password = password;
+
+        self
+    }
+
+    /// Makes clients of this `Server` connect through WebSocket, one IRC line per frame.
+    ///
+    /// Example: `Server::new(addr, 8097).with_websocket(true);`.
+    pub fn with_websocket(mut self, websocket: bool) -> Server {
+        self.websocket = websocket;
+
+        self
+    }
+
+    /// Makes only operators able to register on this `Server`.
+    ///
+    /// Example: `Server::unix("/run/rustyrc/bots.sock").with_oper_only(true);`.
+    pub fn with_oper_only(mut self, oper_only: bool) -> Server {
+        self.oper_only = oper_only;
+
+        self
+    }
+
+    /// Public function creating a Server for each address in `addr` Setting, listening on `port` Setting,
+    ///
+    /// `addr` is a comma separated list of IPv4 addresses, IPv6 addresses or hostnames,
+    /// hostnames are resolved once, here.
     ///
     /// Example:
     /// ```rust
     /// let connection = &mut establish_connection();
-    /// let server = Server::from_settings(get_setting(connection, "ip"), get_setting(connection, "port"));
+    /// let servers = Server::from_settings(connection.get_setting("ip").unwrap(), connection.get_setting("port").unwrap()).unwrap();
     /// ```
-    pub fn from_settings(addr: Setting, port: Setting) -> Server {
-        return Server::new(
-            addr.content.as_str(),
-            port.content.parse().unwrap()
-        )
+    pub fn from_settings(addr: Setting, port: Setting) -> Result<Vec<Server>, String> {
+        let port: u16 = port.content.trim().parse()
+            .map_err(|_| format!("Invalid port: {}", port.content.trim()))?;
+
+        Server::resolve(addr.content.as_str(), port)
     }
 
-    /// This function is used to parse IPv4 `&str` into `IpAddr::V4`.
+    /// Public function creating a Server for each address in `addr`, listening on `port`,
     ///
-    /// Example: `parse_addr("127.0.0.1")`.
-    fn parse_addr(addr: &str) -> IpAddr {
-        // Split str argument into Vec<&str>
-        let split_addr: Vec<&str> = addr.split(".").collect();
-
-        // Stupido checks
-        let mut panic = false;
-        if split_addr.clone().len() > 4 {
-            panic = true;
-        };
-        for i in split_addr.clone() {
-            let j = i.parse::<u32>();
-
-            if j.is_err() {
-                panic = true
+    /// `addr` is a comma separated list of IPv4 addresses, IPv6 addresses or hostnames,
+    /// hostnames are resolved once, here.
+    ///
+    /// Example: `Server::resolve("127.0.0.1,::1", 6667).unwrap();`.
+    pub fn resolve(addr: &str, port: u16) -> Result<Vec<Server>, String> {
+        let mut servers: Vec<Server> = Vec::new();
+
+        for addr in addr.split(",").map(str::trim).filter(|addr| ! addr.is_empty()) {
+            for addr in Server::parse_addr(addr, port)? {
+                if ! servers.iter().any(|server| server.addr == addr) {
+                    servers.push(Server::new(addr, port));
+                }
             }
-            if j.unwrap() > 254 {
-                panic = true;
-            };
         }
 
-        if panic {
-            panic!("Given IP address for server seems invalid.");
-        };
+        if servers.is_empty() {
+            return Err("No address to listen on".to_string());
+        }
+
+        Ok(servers)
+    }
+
+    /// This function is used to parse an IPv4 address, an IPv6 address (brackets are optional) or a hostname,
+    /// which is resolved into every address it points to.
+    ///
+    /// Example: `parse_addr("::", 6667)`, `parse_addr("irc.example.com", 6667)`.
+    fn parse_addr(addr: &str, port: u16) -> Result<Vec<IpAddr>, String> {
+        if let Ok(ip) = addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        let addrs: Vec<IpAddr> = (addr, port).to_socket_addrs()
+            .map_err(|error| format!("Could not resolve {}: {}", addr, error))?
+            .map(|socket| socket.ip())
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(format!("Could not resolve {}: no address found", addr));
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Public function formatting `ip` to be sent as a host in replies (311, 314...),
+///
+/// IPv6 addresses starting with `:` are prefixed with `0`, so they are not taken for a trailing parameter.
+///
+/// Example: `format_host("::1")` returns `0::1`.
+pub fn format_host(ip: &str) -> String {
+    if ip.starts_with(':') {
+        "0".to_string() + ip
+    } else {
+        ip.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_text_wraps_on_words() {
+        assert_eq!(wrap_text("Hello World", 5), vec!["Hello", "World"]);
+        assert_eq!(wrap_text("Hello World", 11), vec!["Hello World"]);
+        assert_eq!(wrap_text("a b c d e", 3), vec!["a b", "c d", "e"]);
+    }
+
+    #[test]
+    fn wrap_text_cuts_long_words() {
+        assert_eq!(wrap_text("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(wrap_text("ab cdefgh", 4), vec!["ab", "cdef", "gh"]);
+        // Width is counted in characters, not bytes
+        assert_eq!(wrap_text("éééééé", 3), vec!["ééé", "ééé"]);
+    }
+
+    #[test]
+    fn wrap_text_keeps_line_breaks() {
+        assert_eq!(wrap_text("Welcome\n\nBe nice", 80), vec!["Welcome", "", "Be nice"]);
+        assert_eq!(wrap_text("Windows\r\nline", 80), vec!["Windows", "line"]);
+        assert!(wrap_text("", 80).is_empty());
+    }
 
-        // Use splits to build IpAddr
-        return IpAddr::V4(Ipv4Addr::new(
-            split_addr[0].to_string().parse().unwrap(),
-            split_addr[1].to_string().parse().unwrap(),
-            split_addr[2].to_string().parse().unwrap(),
-            split_addr[3].to_string().parse().unwrap(),
-        ));
+    #[test]
+    fn first_line_stops_at_line_breaks() {
+        assert_eq!(first_line("bye\r\nKILL x"), "bye");
+        assert_eq!(first_line("bye\rPRIVMSG #general :hi"), "bye");
+        assert_eq!(first_line("bye\0x"), "bye");
+        assert_eq!(first_line("see you later"), "see you later");
+        assert_eq!(first_line(""), "");
     }
 }
@@ -0,0 +1,371 @@
+//! # RustyRC Limits
+//!
+//! File containing limits applied to clients so one of them can't take the server down.
+//!
+//! Flood control is modeled on ircd "fakelag": every line adds a penalty to a lag timer,
+//! once lag goes over the allowed burst, lines are delayed (client is made to wait as if the server was lagging),
+//! clients lagging too much are disconnected for `Excess Flood`.
+//!
+//! Connection limits are checked before a client even gets a thread:
+//! a global number of clients, a number of clients per IP (clones) and a reconnect throttle,
+//! IPs in exempted CIDR ranges only count toward the global limit.
+//! IPv6 clients are counted per /64, the smallest network usually given to a single host.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use ipnet::IpNet;
+use log::warn;
+use crate::rirc_storage::Storage;
+
+/// Per-connection flood control, `check()` has to be called for every line received.
+pub struct FloodControl {
+    lag_until: Instant, // time at which client will have "paid" for every line it sent
+    penalty: Duration, // lag added by a line
+    burst: Duration, // lag allowed before lines get delayed
+    max: Duration, // lag after which client gets disconnected
+}
+
+impl FloodControl {
+    /// Create a `FloodControl` from `penalty` per line, letting `burst` lines through without delay,
+    /// and disconnecting clients once `max` more lines are waiting.
+    ///
+    /// Example: `FloodControl::new(Duration::from_secs(2), 5, 20);`.
+    pub fn new(penalty: Duration, burst: u32, max: u32) -> FloodControl {
+        FloodControl {
+            lag_until: Instant::now(),
+            penalty,
+            burst: penalty * burst,
+            max: penalty * (burst + max),
+        }
+    }
+
+    /// Public function creating a `FloodControl` from settings:
+    /// - `flood_delay`: penalty of a line in milliseconds, 2000 by default,
+    /// - `flood_burst`: lines sent without delay, 5 by default,
+    /// - `flood_max`: delayed lines after which client is disconnected, 20 by default.
+    ///
+    /// Example:
+    /// ```rust
+    /// let connection = &mut establish_connection();
+    /// let flood_control = FloodControl::from_settings(connection);
+    /// ```
+    pub fn from_settings(connection: &mut dyn Storage) -> FloodControl {
+        let mut setting = |key: &str, default: u32| connection.get_setting(key)
+            .map(|setting| setting.content.trim().parse().unwrap_or(default))
+            .unwrap_or(default);
+
+        let penalty = Duration::from_millis(u64::from(setting("flood_delay", 2000)));
+        let burst = setting("flood_burst", 5);
+        let max = setting("flood_max", 20);
+
+        FloodControl::new(penalty, burst, max)
+    }
+
+    /// Accounts for a new line received at `now`, returning how long it has to be delayed,
+    ///
+    /// Lines have to be checked as soon as they arrive (not once previous ones were handled), so lag of a client
+    /// sending faster than it's served keeps growing, it returns `Err(())` when client is flooding and has to be disconnected.
+    pub fn check(&mut self, now: Instant) -> Result<Duration, ()> {
+        self.lag_until = self.lag_until.max(now) + self.penalty;
+        let lag = self.lag_until - now;
+
+        if lag > self.max {
+            return Err(());
+        }
+
+        Ok(lag.saturating_sub(self.burst))
+    }
+}
+
+/// Connection limits shared by every listener, `accept()` has to be called for every new connection.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    rules: Arc<RwLock<LimitRules>>,
+    state: Arc<Mutex<ConnectionCounts>>,
+}
+
+/// Limits themselves, they can be changed while connections are counted (see `ConnectionLimits::replace()`).
+#[derive(Clone)]
+struct LimitRules {
+    max_clients: usize, // connections allowed at once
+    max_per_ip: usize, // connections allowed at once from the same IP
+    exempt: Vec<IpNet>, // ranges exempted from `max_per_ip` and throttle
+    reconnect_max: usize, // connections allowed from the same IP within `reconnect_time`
+    reconnect_time: Duration,
+}
+
+/// Connections currently open and recently accepted, shared by every `ConnectionLimits` clone.
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>, // by `clone_key()`
+    recent: HashMap<IpAddr, VecDeque<Instant>>, // by `clone_key()`
+}
+
+/// Connection accepted by `ConnectionLimits::accept()`, it's released when dropped (whenever its thread ends).
+pub struct ConnectionSlot {
+    addr: Option<IpAddr>, // `clone_key()` of the address connection came from, none for local connections
+    state: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl ConnectionLimits {
+    /// Public function creating `ConnectionLimits` from settings:
+    /// - `max_clients`: connections allowed at once, 1000 by default,
+    /// - `max_per_ip`: connections allowed at once from the same IP (the same /64 for IPv6), 5 by default,
+    /// - `clone_exempt`: comma separated CIDR ranges exempted from `max_per_ip` and throttle (e.g. `127.0.0.0/8,::1/128`),
+    /// - `reconn_max`: connections allowed from the same IP within `reconn_time`, 5 by default,
+    /// - `reconn_time`: in seconds, 60 by default.
+    ///
+    /// Example:
+    /// ```rust
+    /// let connection = &mut establish_connection();
+    /// let limits = ConnectionLimits::from_settings(connection);
+    /// ```
+    pub fn from_settings(connection: &mut dyn Storage) -> ConnectionLimits {
+        let mut setting = |key: &str, default: usize| connection.get_setting(key)
+            .map(|setting| setting.content.trim().parse().unwrap_or(default))
+            .unwrap_or(default);
+
+        let max_clients = setting("max_clients", 1000);
+        let max_per_ip = setting("max_per_ip", 5);
+        let reconnect_max = setting("reconn_max", 5);
+        let reconnect_time = Duration::from_secs(u64::try_from(setting("reconn_time", 60)).unwrap());
+
+        let mut exempt = Vec::new();
+        if let Ok(setting) = connection.get_setting("clone_exempt") {
+            for range in setting.content.split(",").map(str::trim).filter(|range| ! range.is_empty()) {
+                match range.parse::<IpNet>() {
+                    Ok(range) => exempt.push(range),
+                    Err(_) => warn!("Ignoring invalid CIDR range in clone_exempt: {}", range),
+                }
+            }
+        }
+
+        ConnectionLimits {
+            rules: Arc::new(RwLock::new(LimitRules {
+                max_clients,
+                max_per_ip,
+                exempt,
+                reconnect_max,
+                reconnect_time,
+            })),
+            state: Arc::new(Mutex::new(ConnectionCounts::default())),
+        }
+    }
+
+    /// Applies limits of `limits` to every clone of these `ConnectionLimits`,
+    /// connections already open are still counted (and kept, even if there are now too many of them).
+    ///
+    /// Example: `limits.replace(&ConnectionLimits::from_settings(connection));`.
+    pub fn replace(&self, limits: &ConnectionLimits) {
+        let rules = limits.rules.read().unwrap().clone();
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Accounts for a new connection from `addr`,
+    ///
+    /// Returns a `ConnectionSlot` to hold until connection is closed, or why connection is refused.
+    pub fn accept(&self, addr: IpAddr) -> Result<ConnectionSlot, &'static str> {
+        let rules = self.rules.read().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let exempt = rules.exempt.iter().any(|range| range.contains(&addr));
+        let addr = clone_key(addr);
+
+        if state.total >= rules.max_clients {
+            return Err("Server is full");
+        }
+
+        if ! exempt {
+            if state.per_ip.get(&addr).copied().unwrap_or(0) >= rules.max_per_ip {
+                return Err("Too many connections from your IP");
+            }
+
+            // Forgetting connections older than `reconnect_time`
+            let reconnect_time = rules.reconnect_time;
+            state.recent.retain(|_, times| {
+                while times.front().is_some_and(|time| now.duration_since(*time) > reconnect_time) {
+                    times.pop_front();
+                }
+                ! times.is_empty()
+            });
+
+            let recent = state.recent.entry(addr).or_default();
+            if recent.len() >= rules.reconnect_max {
+                return Err("Reconnecting too fast, throttled");
+            }
+            recent.push_back(now);
+        }
+
+        state.total += 1;
+        *state.per_ip.entry(addr).or_insert(0) += 1;
+
+        Ok(ConnectionSlot { addr: Some(addr), state: self.state.clone() })
+    }
+
+    /// Accounts for a new connection on a Unix domain socket, like `accept()` but only `max_clients` applies:
+    /// local clients have no IP of their own to be limited or throttled by.
+    pub fn accept_local(&self) -> Result<ConnectionSlot, &'static str> {
+        let rules = self.rules.read().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        if state.total >= rules.max_clients {
+            return Err("Server is full");
+        }
+
+        state.total += 1;
+
+        Ok(ConnectionSlot { addr: Option::None, state: self.state.clone() })
+    }
+}
+
+/// Returns what connections from `addr` are counted by: `addr` itself for IPv4, its /64 network for IPv6.
+fn clone_key(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => addr,
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u128::MAX >> 64))),
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        state.total -= 1;
+        if let Some(addr) = self.addr {
+            if let Some(count) = state.per_ip.get_mut(&addr) {
+                *count -= 1;
+                if *count == 0 {
+                    state.per_ip.remove(&addr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_clients: usize, max_per_ip: usize, exempt: &[&str], reconnect_max: usize) -> ConnectionLimits {
+        ConnectionLimits {
+            rules: Arc::new(RwLock::new(LimitRules {
+                max_clients,
+                max_per_ip,
+                exempt: exempt.iter().map(|range| range.parse().unwrap()).collect(),
+                reconnect_max,
+                reconnect_time: Duration::from_secs(60),
+            })),
+            state: Arc::new(Mutex::new(ConnectionCounts::default())),
+        }
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn flood_control_lets_burst_through() {
+        let mut flood_control = FloodControl::new(Duration::from_secs(2), 5, 20);
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(flood_control.check(now), Ok(Duration::ZERO));
+        }
+
+        assert_eq!(flood_control.check(now), Ok(Duration::from_secs(2)));
+        assert_eq!(flood_control.check(now), Ok(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn flood_control_disconnects_after_max() {
+        let mut flood_control = FloodControl::new(Duration::from_secs(2), 5, 20);
+        let now = Instant::now();
+
+        for _ in 0..25 {
+            assert!(flood_control.check(now).is_ok());
+        }
+
+        assert_eq!(flood_control.check(now), Err(()));
+    }
+
+    #[test]
+    fn flood_control_forgets_lag_over_time() {
+        let mut flood_control = FloodControl::new(Duration::from_secs(2), 5, 20);
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            flood_control.check(now).unwrap();
+        }
+
+        // Lag went down by 20 seconds, to 0
+        assert_eq!(flood_control.check(now + Duration::from_secs(20)), Ok(Duration::ZERO));
+
+        // One line per penalty is never delayed
+        for second in (22..100).step_by(2) {
+            assert_eq!(flood_control.check(now + Duration::from_secs(second)), Ok(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn connection_limits_count_clones_per_ip() {
+        let limits = limits(100, 2, &[], 100);
+
+        let _first = limits.accept(ip("192.0.2.1")).unwrap();
+        let second = limits.accept(ip("192.0.2.1")).unwrap();
+        assert!(limits.accept(ip("192.0.2.1")).is_err());
+        assert!(limits.accept(ip("192.0.2.2")).is_ok());
+
+        // Slots are released when dropped
+        drop(second);
+        assert!(limits.accept(ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn connection_limits_count_ipv6_per_64() {
+        let limits = limits(100, 2, &[], 100);
+
+        let _first = limits.accept(ip("2001:db8:1:2::1")).unwrap();
+        let _second = limits.accept(ip("2001:db8:1:2:ffff:ffff:ffff:ffff")).unwrap();
+        assert!(limits.accept(ip("2001:db8:1:2::3")).is_err());
+        assert!(limits.accept(ip("2001:db8:1:3::1")).is_ok());
+    }
+
+    #[test]
+    fn connection_limits_exempt_cidr_ranges() {
+        let limits = limits(4, 1, &["10.0.0.0/8", "fd00::/8"], 1);
+
+        let _first = limits.accept(ip("10.1.2.3")).unwrap();
+        let _second = limits.accept(ip("10.1.2.3")).unwrap();
+        let _third = limits.accept(ip("fd00::1")).unwrap();
+
+        // Only the global limit applies to exempted ranges
+        let _fourth = limits.accept(ip("10.1.2.3")).unwrap();
+        assert_eq!(limits.accept(ip("10.1.2.3")).err(), Some("Server is full"));
+    }
+
+    #[test]
+    fn connection_limits_throttle_reconnections() {
+        let limits = limits(100, 100, &[], 2);
+
+        drop(limits.accept(ip("192.0.2.1")).unwrap());
+        drop(limits.accept(ip("192.0.2.1")).unwrap());
+        assert_eq!(limits.accept(ip("192.0.2.1")).err(), Some("Reconnecting too fast, throttled"));
+    }
+
+    #[test]
+    fn connection_limits_only_count_local_clients_globally() {
+        let limits = limits(3, 1, &[], 1);
+
+        // Local clients don't use up limits of 127.0.0.1
+        let _first = limits.accept_local().unwrap();
+        let second = limits.accept_local().unwrap();
+        let _third = limits.accept(ip("127.0.0.1")).unwrap();
+        assert_eq!(limits.accept_local().err(), Some("Server is full"));
+
+        drop(second);
+        assert!(limits.accept_local().is_ok());
+    }
+}
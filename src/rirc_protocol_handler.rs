@@ -3,33 +3,68 @@
 //! File containing functions that will interpret commands as sent by clients, each command has it's own function
 //!
 //! Currently supports most critical commands, WIP for more...
-
-use std::net::TcpStream;
-use diesel::MysqlConnection;
+//!
+//! Who is logged in and who is in which channel is read from live state (see `rirc_state`),
+//! changes to accounts and nick history are written to database in the background (see `persist()`).
+
+use std::time::{Duration, Instant};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use crate::rirc_config::is_operator;
+use crate::rirc_conn_handler::{client_certfp, send_to};
 use crate::rirc_lib::*;
 use crate::rirc_lib::Commands::*;
 use crate::rirc_lib::IrcError::*;
-use crate::rirc_message_handler::wait_for_message;
-use std::thread::spawn;
+use crate::rirc_message_handler::send_to_channel;
+use crate::rirc_rehash;
+use crate::rirc_state::{persist, state, LiveUser};
+use crate::rirc_storage::Storage;
 
-/// Public function handling protocol and sending each requests to the right function depending on the command
-pub fn worker(connection: &mut MysqlConnection, request: Request, addr: String, thread_id: i32, stream: TcpStream) -> Result<Response, IrcError> {
-    if is_banned(connection, addr.as_str()) {
+/// Public function handling protocol and sending each requests to the right function depending on the command,
+///
+/// Until `session` is registered, only commands needed for registration are handled,
+/// others are refused with ERR_NOTREGISTERED.
+pub fn worker(connection: &mut dyn Storage, request: Request, session: &mut Session) -> Result<Response, IrcError> {
+    if is_banned(session.addr.as_str()) {
         return Err(YoureBannedCreep);
     }
 
+    if ! session.registered {
+        return match request.command {
+            AUTHENTICATE => authenticate(connection, session, request.content),
+            CAP => cap(connection, session, request.content),
+            NICK => nick(connection, session, request.content),
+            OPER => oper(session, request.content),
+            PASS => pass(session, request.content),
+            PING => ping(request.content),
+            PONG => pong(session),
+            QUIT => quit(session, request.content),
+            USER => user(connection, session, request.content),
+
+            _ => Err(NotRegistered),
+        }
+    }
+
+    let thread_id = session.thread_id;
+
     return match request.command {
-        JOIN => join(connection, thread_id, request.clone().content, stream),
-        MOTD => motd(connection, thread_id), // TODO
-        NAMES => names(connection, thread_id, request.clone().content),
-        NICK => nick(connection, request.content, addr, thread_id),
-        PART => part(connection, thread_id, request.clone().content),
+        AUTHENTICATE => authenticate(connection, session, request.content),
+        CAP => cap(connection, session, request.content),
+        CERTFP => certfp(connection, session, request.content),
+        JOIN => join(thread_id, request.clone().content),
+        MOTD => motd(connection, thread_id),
+        NAMES => names(thread_id, request.clone().content),
+        NICK => nick(connection, session, request.content),
+        OPER => oper(session, request.content),
+        PART => part(thread_id, request.clone().content),
+        PASS => Err(AlreadyRegistred),
         PING => ping(request.content),
-        PONG => unimplemented(), // Don't reply to pongs otherwise we will just massively ping pong all day
-        PRIVMSG => privmsg(connection, thread_id, request.content),
-        QUIT => quit(connection, thread_id),
-        USER => user(connection, request.content),
-        WHOIS => whois(connection, request.content, thread_id),
+        PONG => pong(session), // Don't reply to pongs otherwise we will just massively ping pong all day
+        PRIVMSG => privmsg(thread_id, request.content),
+        QUIT => quit(session, request.content),
+        REHASH => rehash(connection, session),
+        USER => Err(AlreadyRegistred),
+        WHOIS => whois(request.content, session),
         WHOWAS => whowas(connection, request.content, thread_id),
 
         // TODO: KICK, KILL, USERS, SERVLIST (?)
@@ -37,49 +72,262 @@ pub fn worker(connection: &mut MysqlConnection, request: Request, addr: String,
     }
 }
 
-/// Handling users joining channels
-fn join(connection: &mut MysqlConnection, thread_id: i32, content: String, stream: TcpStream) -> Result<Response,IrcError> {
-    // Expecting message such as
-    // JOIN <channel>{,<channel>} [<key>{,<key>}]
+/// Handling SASL authentication (IRCv3), only the EXTERNAL mechanism is supported:
+/// client is logged into the account its TLS client certificate fingerprint is attached to (see `certfp()`).
+fn authenticate(connection: &mut dyn Storage, session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expecting requests in this form (IRCv3):
+    // AUTHENTICATE <mechanism>
+    // AUTHENTICATE <base64 payload>|+|*
+    let parameter = first_word(content.as_str()).trim_start_matches(':').to_string();
+    let target = session.nick.clone().unwrap_or("*".to_string());
+
+    if parameter.is_empty() {
+        return Err(NeedMoreParams);
+    }
+
+    // Client aborted authentication
+    if parameter == "*" {
+        session.sasl_mechanism = Option::None;
+        return Ok(Response::new(":localhost 906 ".to_string() + target.as_str() + " :SASL authentication aborted"));
+    }
 
-    let channel = match get_channel(connection, first_word(&content.clone())) {
-        Ok(channel) => { channel }
-        Err(_) => { return Err(NoSuchChannel); }
+    if session.account.is_some() {
+        return Ok(Response::new(":localhost 907 ".to_string() + target.as_str() + " :You have already authenticated using SASL"));
+    }
+
+    let failed = ":localhost 904 ".to_string() + target.as_str() + " :SASL authentication failed";
+
+    // First message is the mechanism
+    if session.sasl_mechanism.is_none() {
+        if parameter.to_uppercase() != "EXTERNAL" {
+            return Ok(Response::new(":localhost 908 ".to_string() + target.as_str() + " EXTERNAL :are available SASL mechanisms\n" + failed.as_str()));
+        }
+
+        session.sasl_mechanism = Some("EXTERNAL".to_string());
+        return Ok(Response::new("AUTHENTICATE +".to_string()));
+    }
+
+    session.sasl_mechanism = Option::None;
+
+    // EXTERNAL payload is either empty ("+") or the account client wants to log into
+    let authzid = match parameter.as_str() {
+        "+" => "".to_string(),
+        payload => match BASE64.decode(payload).ok().and_then(|authzid| String::from_utf8(authzid).ok()) {
+            Some(authzid) => authzid,
+            Option::None => return Ok(Response::new(failed)),
+        }
+    };
+
+    let account = match session.certfp.clone().map(|certfp| connection.get_user_from_certfp(certfp.as_str())) {
+        Some(Ok(user)) if authzid.is_empty() || authzid.eq_ignore_ascii_case(user.nick.as_str()) => user.nick,
+        _ => return Ok(Response::new(failed)),
     };
 
-    // Preparing to send a message such as ":WiZ JOIN #Twilight_zone" in the channel
-    let user = get_user_from_thread_id(connection, &thread_id).unwrap();
-    let line = create_user_line(user.clone(), "JOIN :") + channel.clone().name.as_str();
+    // Logging in protects nicks of the account (see `check_account()`), operator status only comes from OPER
+    session.account = Some(account.clone());
+
+    Ok(Response::new(":localhost 900 ".to_string() + target.as_str() + " " + target.as_str() + "!" + target.as_str() + "@" + session.addr.as_str() + " " + account.as_str() + " :You are now logged in as " + account.as_str()
+        + "\n:localhost 903 " + target.as_str() + " :SASL authentication successful"))
+}
+
+/// Handling capability negotiation,
+///
+/// Only `sasl` is supported, offered to clients connected through TLS,
+/// `CAP LS` and `CAP REQ` hold registration until client sends `CAP END`.
+fn cap(connection: &mut dyn Storage, session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expecting request in this form (IRCv3):
+    // CAP <subcommand> [:<capabilities>]
+    let subcommand = first_word(content.as_str()).to_uppercase();
+    let target = session.nick.clone().filter(|_| session.registered).unwrap_or("*".to_string());
+
+    // SASL EXTERNAL needs a client certificate, so it's only offered through TLS
+    let available: Vec<&str> = if session.secure { vec!["sasl"] } else { vec![] };
+
+    match subcommand.as_str() {
+        "LS" => {
+            if ! session.registered {
+                session.cap_negotiating = true;
+            }
 
-    // Sending
-    add_message(connection, channel.clone(), line.as_str()).unwrap();
+            // Values are only sent to clients supporting CAP version 302
+            let version = content.split_whitespace().nth(1).and_then(|version| version.parse::<u32>().ok()).unwrap_or(0);
+            let capabilities: Vec<String> = available.iter()
+                .map(|capability| if *capability == "sasl" && version >= 302 { "sasl=EXTERNAL".to_string() } else { capability.to_string() })
+                .collect();
 
-    // Add membership to the table, so child thread knows what to do
-    create_membership(connection, user, channel.clone());
+            Ok(Response::new(":localhost CAP ".to_string() + target.as_str() + " LS :" + capabilities.join(" ").as_str()))
+        }
+        "LIST" => Ok(Response::new(":localhost CAP ".to_string() + target.as_str() + " LIST :" + session.caps.join(" ").as_str())),
+        "REQ" => {
+            if ! session.registered {
+                session.cap_negotiating = true;
+            }
 
-    spawn(|| {
-        let connection = &mut establish_connection();
+            let requested = content.splitn(2, " ").nth(1).unwrap_or("").trim_start_matches(':').trim().to_string();
 
-        // Thread will finally start waiting for messages
-        wait_for_message(connection, stream);
-    });
+            // Capabilities are acknowledged all together, or refused all together
+            if requested.is_empty() || ! requested.split_whitespace().all(|capability| available.contains(&capability.trim_start_matches('-'))) {
+                return Ok(Response::new(":localhost CAP ".to_string() + target.as_str() + " NAK :" + requested.as_str()));
+            }
+
+            for capability in requested.split_whitespace() {
+                match capability.strip_prefix('-') {
+                    Some(capability) => session.caps.retain(|enabled| enabled != capability),
+                    Option::None => if ! session.caps.iter().any(|enabled| enabled == capability) {
+                        session.caps.push(capability.to_string())
+                    }
+                }
+            }
+
+            Ok(Response::new(":localhost CAP ".to_string() + target.as_str() + " ACK :" + requested.as_str()))
+        }
+        "END" => {
+            session.cap_negotiating = false;
+
+            register(connection, session)
+        }
+        _ => Ok(Response::new(":localhost 410 ".to_string() + target.as_str() + " " + subcommand.as_str() + " :Invalid CAP command")),
+    }
+}
+
+/// Managing TLS client certificate fingerprints attached to the account user is logged into, used to log in with SASL EXTERNAL,
+///
+/// - `CERTFP ADD` attaches the fingerprint of the certificate client is connected with,
+///   users not logged in can only attach one to an account they created (see `Session::created`), they are then logged into it,
+/// - `CERTFP DEL <fingerprint>` detaches a fingerprint,
+/// - `CERTFP LIST` lists attached fingerprints.
+fn certfp(connection: &mut dyn Storage, session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expecting request in this form:
+    // CERTFP <ADD|DEL|LIST> [<fingerprint>]
+    let content_vec: Vec<&str> = content.split_whitespace().collect();
+
+    if content_vec.is_empty() {
+        return Err(NeedMoreParams);
+    }
+
+    let nick = session.nick.clone().unwrap();
+    let notice = ":localhost NOTICE ".to_string() + nick.as_str() + " :";
+
+    // Fingerprints are attached to the account user logged into
+    let subcommand = content_vec[0].to_uppercase();
+    let account = match session.account.clone() {
+        Some(account) => account,
+        // An account created by this session is claimed by its first fingerprint, others need a login
+        Option::None if subcommand == "ADD" && session.created.as_ref().is_some_and(|created| created.eq_ignore_ascii_case(nick.as_str())) => nick.clone(),
+        Option::None => return Ok(Response::new(notice + "You are not logged in, log in with SASL EXTERNAL first")),
+    };
+
+    match subcommand.as_str() {
+        "ADD" => {
+            let fingerprint = match session.certfp.clone() {
+                Some(fingerprint) => fingerprint,
+                Option::None => return Ok(Response::new(notice + "You are not using a client certificate")),
+            };
+
+            match connection.get_user_from_certfp(fingerprint.as_str()) {
+                Ok(owner) if owner.nick.eq_ignore_ascii_case(account.as_str()) => Ok(Response::new(notice + "Fingerprint " + fingerprint.as_str() + " is already attached to you")),
+                Ok(_) => Ok(Response::new(notice + "Fingerprint " + fingerprint.as_str() + " is already attached to another user")),
+                Err(_) => {
+                    let line = notice + "Fingerprint " + fingerprint.as_str() + " attached to " + account.as_str();
+
+                    session.account = Some(account.clone());
+
+                    persist(move |connection| {
+                        if let Ok(user) = connection.get_user_from_nick(account.as_str()) {
+                            connection.create_certfp(user, fingerprint.as_str());
+                        }
+                    });
+
+                    Ok(Response::new(line))
+                }
+            }
+        }
+        "DEL" => {
+            let fingerprint = match content_vec.get(1) {
+                Some(fingerprint) => fingerprint.to_lowercase(),
+                Option::None => return Err(NeedMoreParams),
+            };
+
+            if ! connection.get_user_from_certfp(fingerprint.as_str()).is_ok_and(|owner| owner.nick.eq_ignore_ascii_case(account.as_str())) {
+                return Ok(Response::new(notice + "Fingerprint " + fingerprint.as_str() + " is not attached to you"));
+            }
+
+            let line = notice + "Fingerprint " + fingerprint.as_str() + " detached";
+
+            persist(move |connection| {
+                if let Ok(user) = connection.get_user_from_nick(account.as_str()) {
+                    connection.delete_certfp(user, fingerprint.as_str());
+                }
+            });
+
+            Ok(Response::new(line))
+        }
+        "LIST" => {
+            let certfps = match connection.get_user_from_nick(account.as_str()) {
+                Ok(user) => connection.get_user_certfps(user),
+                Err(_) => Vec::new(),
+            };
+
+            let mut lines: Vec<String> = certfps
+                .iter()
+                .map(|certfp| notice.clone() + certfp.fingerprint.as_str())
+                .collect();
+
+            lines.push(notice + "End of fingerprint list");
+
+            Ok(Response::new(lines.join("\n")))
+        }
+        _ => Err(NeedMoreParams),
+    }
+}
+
+/// Handling users joining channels
+fn join(thread_id: i32, content: String) -> Result<Response,IrcError> {
+    // Expecting message such as
+    // JOIN <channel>{,<channel>} [<key>{,<key>}]
+
+    // User becomes a member, so it gets messages sent to channel
+    let (line, channel) = {
+        let mut state = state();
+
+        // Preparing to send a message such as ":WiZ JOIN #Twilight_zone" in the channel
+        let line = create_user_line(state.user(thread_id).unwrap(), "JOIN :");
+        let channel = state.join(thread_id, first_word(&content.clone()))?.clone();
+
+        (line + channel.name.as_str(), channel)
+    };
+
+    // Sending to members already in channel
+    send_to_channel(&channel, line.as_str(), thread_id);
 
     // Preparing to return channel's MOTD to user
     let topic = channel.topic;
     let line = "332 :".to_string() + topic.as_str();
 
-    let res = line + "\n" + names(connection, thread_id, channel.name).unwrap().content.as_str();
+    let res = line + "\n" + names(thread_id, channel.name).unwrap().content.as_str();
 
     Ok(Response::new(res))
 }
 
-/// Replying to MOTD commands
-fn motd(connection: &mut MysqlConnection, thread_id: i32) -> Result<Response,IrcError> {
+/// Replying to MOTD commands,
+///
+/// MOTD (see `get_motd()`) is sent in lines of at most 80 characters, or ERR_NOMOTD is returned when none is set.
+fn motd(connection: &mut dyn Storage, thread_id: i32) -> Result<Response,IrcError> {
     // RPL_MOTDSTART: 375
     // RPL_MOTD: 372
     // RPL_ENDOFMOTD: 376
 
-    Ok(Response::new("".to_string()))
+    let motd = get_motd(connection).ok_or(NoMotd)?;
+
+    let nick = state().user(thread_id).unwrap().nick.clone();
+
+    let mut lines = vec![":localhost 375 ".to_string() + nick.as_str() + " :- localhost Message of the day - "];
+    for line in wrap_text(motd.as_str(), 80) {
+        lines.push(":localhost 372 ".to_string() + nick.as_str() + " :- " + line.as_str());
+    }
+    lines.push(":localhost 376 ".to_string() + nick.as_str() + " :End of /MOTD command.");
+
+    Ok(Response::new(lines.join("\n")))
 }
 
 /// Replying to NAMES commands,
@@ -87,118 +335,159 @@ fn motd(connection: &mut MysqlConnection, thread_id: i32) -> Result<Response,Irc
 /// Without argument (empty `content`) it will print all channels and logged users,
 ///
 /// With an argument it will print users in said channel.
-fn names(connection: &mut MysqlConnection, thread_id: i32, content: String) -> Result<Response,IrcError> {
+fn names(thread_id: i32, content: String) -> Result<Response,IrcError> {
     // Expecting input as (RFC1459):
     // NAMES [<channel>{,<channel>}]
 
     // RPL_NAMREPLY: 353
     // RPL_ENDOFNAMES: 366
 
-    let user = get_user_from_thread_id(connection, &thread_id).unwrap();
-    let mut res_string = "".to_string();
+    let state = state();
+    let nick = state.user(thread_id).unwrap().nick.clone();
 
-    // expecting answer for all channels
-    if content.is_empty() {
-        for channel in get_all_channels(connection).unwrap() {
-            // 353 "<channel> :[[@|+]<nick> [[@|+]<nick> [...]]]"
-            res_string = ":localhost 353 ".to_string() + user.nick.as_str() + " = " + channel.name.as_str() + " :";
-            for membership in get_all_channel_memberships(connection, channel.id).unwrap() {
-                res_string = res_string + get_user_from_thread_id(connection, &membership.id_user).unwrap().nick.as_str() + " ";
-            }
+    // expecting answer for all channels, or for specific channel
+    let channels = if content.is_empty() {
+        state.channels()
+    } else if content.contains(",") {
+        return Err(TooManyTargets);
+    } else {
+        // target channel doesnt exist
+        vec![state.channel(content.as_str()).ok_or(NoSuchChannel)?]
+    };
 
-            res_string = res_string + "\n:localhost 366 " + user.nick.as_str() + " " + channel.name.as_str() + " :End of /NAMES list.";
-        }
+    let mut lines: Vec<String> = Vec::new();
+
+    for channel in channels {
+        // 353 "<channel> :[[@|+]<nick> [[@|+]<nick> [...]]]"
+        lines.push(":localhost 353 ".to_string() + nick.as_str() + " = " + channel.name.as_str() + " :" + state.member_nicks(channel).join(" ").as_str());
+        lines.push(":localhost 366 ".to_string() + nick.as_str() + " " + channel.name.as_str() + " :End of /NAMES list.");
     }
 
-    // expecting answer for specific channel
-    if content.contains(",") {
-        return Err(TooManyTargets);
+    Ok(Response::new(lines.join("\n")))
+}
+
+/// User choosing a nickname,
+///
+/// Before registration, nickname is only kept in `session` until `register()` claims it,
+/// afterwards user keeps its session and channels under the new nickname, and everyone sharing a channel is told.
+fn nick(connection: &mut dyn Storage, session: &mut Session, content: String) -> Result<Response, IrcError> {
+    let nick = first_word(content.as_str()).trim_start_matches(':');
+
+    if nick.is_empty() {
+        return Err(NoNicknameGiven);
     }
 
-    let channel = content.as_str();
+    check_nick(nick)?;
+
+    if ! session.registered {
+        if state().user_from_nick(nick).is_some() {
+            // A user with same name is already logged in
+            return Err(NicknameInUse);
+        }
+
+        session.nick = Some(nick.to_string());
 
-    // target channel doesnt exist
-    if get_channel(connection, content.as_str()).is_err() {
-        return Err(NoSuchChannel);
+        return register(connection, session);
     }
-    let channel_id = get_channel(connection, content.as_str()).unwrap().id;
 
-    // 353 "<channel> :[[@|+]<nick> [[@|+]<nick> [...]]]"
-    res_string = ":localhost 353 ".to_string() + user.nick.as_str() + " = " + channel + " :";
-    for membership in get_all_channel_memberships(connection, channel_id).unwrap() {
-        res_string = res_string + get_user_from_thread_id(connection, &membership.id_user).unwrap().nick.as_str() + " ";
+    let thread_id = session.thread_id;
+    let user = state().user(thread_id).unwrap().clone();
+
+    if user.nick == nick {
+        return Ok(Response::no_response());
     }
 
-    res_string = res_string + "\n:localhost 366 " + user.nick.as_str() + " " + channel + " :End of /NAMES list.";
+    // A user with same name is already logged in (nicks are compared case insensitively, user may only change case)
+    if state().user_from_nick(nick).is_some_and(|owner| owner.thread_id != thread_id) {
+        return Err(NicknameInUse);
+    }
 
-    Ok(Response::new(res_string))
-}
+    // Nick changes are limited to one every `nick_delay` setting (in seconds, 30 by default)
+    let nick_delay = Duration::from_secs(connection.get_setting("nick_delay")
+        .map(|setting| setting.content.trim().parse().unwrap_or(30))
+        .unwrap_or(30));
 
-/// User logging in
-fn nick(connection: &mut MysqlConnection, content: String, addr: String, thread_id: i32) -> Result<Response, IrcError> {
-    let nick = first_word(content.as_str());
+    if session.last_nick_change.is_some_and(|last_nick_change| last_nick_change.elapsed() < nick_delay) {
+        return Err(NickTooFast);
+    }
 
-    check_nick(connection, nick)?;
+    // Line has to be built before, with old nickname
+    let line = create_user_line(&user, "NICK :") + nick;
 
-    let db_user = get_user_from_nick(connection, nick);
+    let old = user.to_user();
+    let new_nick = nick.to_string();
 
-    // if user already has a nickname
-    match get_user_from_thread_id(connection, &thread_id) {
-        Ok(user) => { set_connected(connection, user, &false) }
-        Err(_) => {}
-    }
+    if user.nick.eq_ignore_ascii_case(nick) {
+        // Only changing case
+        state().rename_user(thread_id, nick)?;
 
-    return match db_user {
-        Ok(db_user) => {
-            if db_user.is_connected {
-                // A user with same name is already logged in
-                Err(NicknameInUse)
-            } else {
-                // A user with same name has already logged in but logged off since then
-                edit_user(connection, &get_current_epoch(), nick, addr.as_str(), &true, &thread_id).unwrap();
-                let res = Response::new(":localhost 001 ".to_string() + nick + " :Welcome!");
-                Ok(res)
+        persist(move |connection| {
+            if let Ok(account) = connection.get_user_from_nick(old.nick.as_str()) {
+                connection.set_nick(account, new_nick.as_str());
             }
+        });
+    } else {
+        // User takes over the account of its new nickname, if any (unless it's protected by fingerprints)
+        check_account(connection, session, nick)?;
+
+        state().rename_user(thread_id, nick)?;
+
+        if connection.get_user_from_nick(nick).is_err() {
+            session.created = Some(nick.to_string());
         }
-        Err(_) => {
-            // Username has never logged in
-            create_user(connection, &get_current_epoch(), nick, nick, addr.as_str(), &true, &false, &thread_id);
-            let res = Response::new(":localhost 001 ".to_string() + nick + " :Welcome!");
-            Ok(res)
-        }
+
+        // Old nickname goes to history, then user logs in again under new one with the same `thread_id`
+        persist(move |connection| {
+            connection.create_whowas(old.clone());
+
+            if let Ok(account) = connection.get_user_from_nick(old.nick.as_str()) {
+                connection.set_connected(account, &false);
+            }
+
+            match connection.get_user_from_nick(new_nick.as_str()) {
+                // A user with same name has already logged in but logged off since then
+                Ok(_) => connection.edit_user(&get_current_epoch(), new_nick.as_str(), old.last_ip.as_str(), &true, &old.thread_id).unwrap(),
+                // Username has never logged in
+                Err(_) => connection.create_user(&get_current_epoch(), new_nick.as_str(), old.real_name.as_str(), old.last_ip.as_str(), &true, &false, &old.thread_id),
+            }
+
+            let account = connection.get_user_from_nick(new_nick.as_str()).unwrap();
+            connection.set_real_name(account, old.real_name.as_str());
+        });
+    }
+
+    session.nick = Some(nick.to_string());
+    session.last_nick_change = Some(Instant::now());
+
+    // Everyone sharing a channel is told, so is user
+    let neighbours = state().neighbours(thread_id);
+    for neighbour in neighbours {
+        send_to(neighbour, Response::new(line.clone()));
     }
+
+    Ok(Response::new(line))
 }
 
 /// Handling user leaving a channel
-fn part(connection: &mut MysqlConnection, thread_id: i32, content: String) -> Result<Response, IrcError> {
+fn part(thread_id: i32, content: String) -> Result<Response, IrcError> {
     let channel_str = first_word(content.as_str());
 
-    let user = get_user_from_thread_id(connection, &thread_id).unwrap();
-
     if channel_str.contains(",") {
         return Err(TooManyChannels);
     }
 
-    if get_channel(connection, channel_str).is_err() {
-        return Err(NoSuchChannel);
-    }
-    let channel = get_channel(connection, channel_str).unwrap();
-
-    let mut user_in_channel = false;
+    // User stops getting messages sent to channel
+    let (line, channel) = {
+        let mut state = state();
 
-    for membership in get_all_user_memberships(connection, user.id).unwrap() {
-        if membership.id_channel == channel.id {
-            user_in_channel = true;
-            break
-        }
-    }
+        let line = create_user_line(state.user(thread_id).unwrap(), "PART ");
+        let channel = state.channel(channel_str).ok_or(NoSuchChannel)?.clone();
+        state.part(thread_id, channel_str)?;
 
-    if ! user_in_channel {
-        return Err(NotOnChannel);
-    }
+        (line + channel.name.as_str(), channel)
+    };
 
-    let line = create_user_line(user, "PART") + channel_str;
-    add_message(connection, channel, line.as_str()).unwrap();
+    send_to_channel(&channel, line.as_str(), thread_id);
 
     Ok(Response::no_response())
 }
@@ -208,35 +497,48 @@ fn ping(content: String) -> Result<Response, IrcError> {
     Ok(Response::new("PONG :".to_string() + content.as_str()))
 }
 
+/// Client answered our PING, it's alive
+fn pong(session: &mut Session) -> Result<Response, IrcError> {
+    session.ping_sent = Option::None;
+
+    Ok(Response::no_response())
+}
+
 /// Handling user sending message to channel
-fn privmsg(connection: &mut MysqlConnection, thread_id: i32, content: String) -> Result<Response,IrcError> {
+fn privmsg(thread_id: i32, content: String) -> Result<Response,IrcError> {
     // Expecting request in this form (RFC 1459):
     // PRIVMSG <receiver>{,<receiver>} <text to be sent>
     let mut content_vec: Vec<&str> = content.split_whitespace().collect();
 
-    let mut receiver = content_vec[0];
-    let receiver_with_hashtag = "#".to_string() + receiver.clone();
+    let receiver = match content_vec.first() {
+        Some(receiver) => *receiver,
+        Option::None => return Err(NoRecipient),
+    };
+
+    if content_vec[1..].join(" ").trim_start_matches(':').is_empty() {
+        return Err(NoTextToSend);
+    }
+    let receiver_with_hashtag = "#".to_string() + receiver;
 
     // Testing channel as both #`receiver` and `receiver`
     // Because some irc client add #, some don't :DDDDDD
-    let channel = match get_channel(connection, receiver) {
-        Ok(channel) => { channel }
-        Err(_) => {
-            match get_channel(connection, receiver_with_hashtag.as_str()) {
-                Ok(receiver_with_hashtag) => { receiver_with_hashtag }
-                Err(_) => { return Err(NoSuchChannel); }
-            }
-        }
-    };
+    let (sender, channel) = {
+        let state = state();
+
+        let channel = state.channel(receiver)
+            .or_else(|| state.channel(receiver_with_hashtag.as_str()))
+            .ok_or(NoSuchChannel)?
+            .clone();
 
-    let sender = get_user_from_thread_id(connection, &thread_id).unwrap();
+        (state.user(thread_id).unwrap().clone(), channel)
+    };
 
     // We won't handle sending to multiple recipients
     if receiver.contains(",") {
         return Err(TooManyTargets)
     }
 
-    let mut message = create_user_line(sender.clone(), "PRIVMSG ") + receiver + " :";
+    let mut message = create_user_line(&sender, "PRIVMSG ") + receiver + " :";
 
     for word in &mut content_vec[1..] {
         for char in word.chars() {
@@ -245,87 +547,198 @@ fn privmsg(connection: &mut MysqlConnection, thread_id: i32, content: String) ->
         message = message + " ";
     }
 
-    add_message(connection, channel, &*message)?;
+    send_to_channel(&channel, message.trim_end(), thread_id);
 
     Ok(Response::no_response())
 }
 
-/// User quitting server,
+/// User quitting server, with an optional quit message,
 ///
-/// It will broadcast to all channels that user is leaving them.
-fn quit(connection: &mut MysqlConnection, thread_id: i32) -> Result<Response, IrcError> {
-    let user = get_user_from_thread_id(connection, &thread_id).unwrap();
+/// User is logged off (see `log_off()`) then sent an ERROR closing the connection.
+fn quit(session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expecting request in this form (RFC 1459):
+    // QUIT [<Quit message>]
+    let message = first_line(content.trim().trim_start_matches(':')).trim_end();
 
-    // [channel] gets replaced by whatever the channel name is inside the function `broadcast_as_user`
-    let line = create_user_line(user.clone(), " PART [channel]");
+    // "Quit: " prefix lets others know it's a message from user, not from server
+    let reason = if message.is_empty() {
+        "Client Quit".to_string()
+    } else {
+        "Quit: ".to_string() + message
+    };
 
-    broadcast_as_user(connection, user.nick.as_str(), line.to_string()).unwrap();
+    log_off(session.thread_id, reason.as_str());
 
-    set_connected(connection, user.clone(), &false);
+    Ok(Response::closing("ERROR :Closing Link: ".to_string() + session.addr.as_str() + " (" + reason.as_str() + ")"))
+}
 
-    delete_user_membership(connection, user);
+/// Logging off user of `thread_id` with a certain `reason`,
+///
+/// Everyone sharing a channel with user is sent a QUIT once, then user leaves all channels,
+/// nick history and account are updated in the background.
+///
+/// Used by QUIT and by `handler()` whenever a connection ends, does nothing if user is already logged off.
+pub fn log_off(thread_id: i32, reason: &str) {
+    let (user, neighbours) = {
+        let mut state = state();
+        let neighbours = state.neighbours(thread_id);
+
+        match state.remove_user(thread_id) {
+            Some(user) => (user, neighbours),
+            Option::None => return,
+        }
+    };
 
-    Ok(Response::new("BYE BYE".to_string()))
+    let line = create_user_line(&user, "QUIT :") + reason;
+
+    for neighbour in neighbours {
+        send_to(neighbour, Response::new(line.clone()));
+    }
+
+    let user = user.to_user();
+
+    persist(move |connection| {
+        connection.create_whowas(user.clone());
+
+        if let Ok(account) = connection.get_user_from_nick(user.nick.as_str()) {
+            connection.set_connected(account, &false);
+        }
+    });
 }
 
-/// User logging in (part2).
+/// Handling OPER: user becomes an operator if `name` and `password` match one of `[[operators]]` (see `rirc_config`),
 ///
-/// Only really used to define real_name, other parameters are ignored.
-///
-/// Will drop an error if first argument (supposedly username) is not a known nickname.
-fn user(connection: &mut MysqlConnection, content: String) -> Result<Response, IrcError> {
-    let content_iter = content.split(" ");
-    // Expected form: (from RFC1459)
-    // <username> <hostname> <servername> <realname>
-    let mut content_vec = content_iter.collect::<Vec<_>>();
+/// Operator status lasts until user disconnects, whatever nick it uses,
+/// it can be sent before registration (operators only listeners, see `register()`).
+fn oper(session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expecting request in this form (RFC 1459):
+    // OPER <name> <password>
+    // RPL_YOUREOPER: 381
+    let content_vec: Vec<&str> = content.split_whitespace().collect();
+
+    if content_vec.len() < 2 {
+        return Err(NeedMoreParams);
+    }
 
-    let nick = content_vec[0];
+    let nick = session.nick.clone().unwrap_or("*".to_string());
 
-    if ! content_vec.len() <= 4 {
-        return Err(NeedMoreParams)
+    // Not an `IrcError`, those close the connection
+    if ! is_operator(content_vec[0], content_vec[1].trim_start_matches(':')) {
+        return Ok(Response::new(":localhost 464 ".to_string() + nick.as_str() + " :Password Incorrect"));
     }
 
-    // if <realname> starts with ":" (declaring a multi word string)
-    let mut real_name = "".to_string();
-    if content_vec[3].starts_with(":") {
-        // collect all parts into real_name
-        for word in &mut content_vec[3..] {
-            for char in word.chars() {
-                real_name = real_name + char.to_string().as_str();
+    session.op = true;
+    let mut res = ":localhost 381 ".to_string() + nick.as_str() + " :You are now an IRC operator";
+
+    // Before registration, +o is part of the modes sent by `register()`
+    if let Some(user) = state().user_mut(session.thread_id) {
+        user.op = true;
+        user.modes.push('o');
+        res = res + "\n:" + nick.as_str() + " MODE " + nick.as_str() + " :+o";
+    }
+
+    Ok(Response::new(res))
+}
+
+/// Storing password sent by client before registration, it's checked by `register()`.
+fn pass(session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expecting request in this form (RFC 1459):
+    // PASS <password>
+    let password = content.trim().trim_start_matches(':');
+
+    if password.is_empty() {
+        return Err(NeedMoreParams);
+    }
+
+    session.password = Some(password.to_string());
+
+    Ok(Response::no_response())
+}
+
+/// Handling REHASH (operators only, see `oper()`): configuration is reloaded from database (see `rirc_rehash`),
+/// errors are sent back as notices, configuration is left unchanged if there's any.
+fn rehash(connection: &mut dyn Storage, session: &Session) -> Result<Response, IrcError> {
+    // Expecting request in this form (RFC 1459):
+    // REHASH
+    // RPL_REHASHING: 382
+    if ! session.op {
+        return Err(NoPrivileges);
+    }
+
+    let nick = session.nick.clone().unwrap_or_default();
+    let mut lines = vec![":localhost 382 ".to_string() + nick.as_str() + " settings :Rehashing"];
+
+    match rirc_rehash::rehash(connection) {
+        Ok(()) => lines.push(":localhost NOTICE ".to_string() + nick.as_str() + " :Configuration reloaded"),
+        Err(errors) => {
+            for error in errors {
+                lines.push(":localhost NOTICE ".to_string() + nick.as_str() + " :REHASH: " + error.as_str());
             }
-            real_name = real_name + " ";
+            lines.push(":localhost NOTICE ".to_string() + nick.as_str() + " :Configuration unchanged");
         }
-    } else {
-        real_name = content_vec[3].to_string();
     }
 
-    // if user doesnt exist or is not logged in
-    let user = match get_user_from_nick(connection, nick) {
-        Ok(user) => { if user.is_connected { user } else { return Err(UnknownError) }}
-        Err(_) => { return  Err(UnknownError) }
+    Ok(Response::new(lines.join("\n")))
+}
+
+/// User logging in (part2).
+///
+/// Only really used to define real_name, other parameters are ignored.
+fn user(connection: &mut dyn Storage, session: &mut Session, content: String) -> Result<Response, IrcError> {
+    // Expected form: (from RFC1459)
+    // <username> <hostname> <servername> <realname>
+    let (parameters, trailing) = match content.split_once(" :") {
+        Some((parameters, trailing)) => (parameters.to_string(), Some(trailing.to_string())),
+        Option::None => (content.clone(), Option::None),
+    };
+    let mut content_vec: Vec<String> = parameters.split_whitespace().map(|word| word.to_string()).collect();
+
+    // <realname> is either a multi word string starting with ":" or a single word
+    let real_name = match trailing {
+        Some(trailing) => trailing,
+        Option::None => content_vec.pop().unwrap_or("".to_string()),
     };
 
-    set_real_name(connection, user, real_name.as_str());
+    if content_vec.len() < 3 || first_line(real_name.trim()).trim().is_empty() {
+        return Err(NeedMoreParams)
+    }
 
-    Ok(Response::new(":localhost 001 ".to_string() + nick + " :Real name stored..."))
+    // Storing no more than `users.real_name` can hold
+    session.real_name = Some(first_line(real_name.trim()).trim_end().chars().take(25).collect());
+
+    register(connection, session)
 }
 
-/// Replying to WHOIS commands, will reply only if user is logged in
-fn whois(connection: &mut MysqlConnection, content: String, w_thread_id: i32) -> Result<Response, IrcError> {
+/// Replying to WHOIS commands, will reply only if user is logged in,
+///
+/// TLS client certificate fingerprint (276) is only shown to the user themselves and to operators (authenticated with OPER).
+fn whois(content: String, session: &Session) -> Result<Response, IrcError> {
     let mut res = Response::new(":localhost ".to_string());
 
-    let sender = get_user_from_thread_id(connection, &w_thread_id).unwrap().nick;
+    let (sender, target) = {
+        let state = state();
+        (state.user(session.thread_id).unwrap().nick.clone(), state.user_from_nick(content.as_str()).cloned())
+    };
+
+    match target {
+        // User is currently logged in
+        Some(user) => {
+            res.content = res.content + "311 " + user.nick.as_str() + " " + user.nick.as_str() + " " + format_host(user.ip.as_str()).as_str() + " " + user.real_name.as_str();
+
+            // User is connected through TLS (+Z)
+            if user.modes.contains('Z') {
+                res.content = res.content + "\n:localhost 671 " + sender.as_str() + " " + user.nick.as_str() + " :is using a secure connection"
+            }
 
-    match get_user_from_nick(connection, content.as_str()) {
-        Ok(user) => {
-            if user.is_connected {
-                // User is currently logged in
-                res.content = res.content + "311 " + user.nick.as_str() + " " + user.nick.as_str() + " " + user.last_ip.as_str() + " " + user.real_name.as_str()
-            } // User is not currently logged in
-            else { res.content = res.content + "401 " + sender.as_str() + " " + content.as_str() + " :No such nick registered" }
+            // User sent a client certificate
+            if session.op || user.thread_id == session.thread_id {
+                if let Some(fingerprint) = client_certfp(user.thread_id) {
+                    res.content = res.content + "\n:localhost 276 " + sender.as_str() + " " + user.nick.as_str() + " :has client certificate fingerprint " + fingerprint.as_str()
+                }
+            }
         }
-        // User has never logged in
-        Err(_) => res.content = res.content + "401 " + sender.as_str() + " " + content.as_str() + " :No such nick registered",
+        // User is not currently logged in
+        Option::None => res.content = res.content + "401 " + sender.as_str() + " " + content.as_str() + " :No such nick registered",
     }
 
     res.content = res.content + "\n:localhost 318 " + sender.as_str() + " " + content.as_str() + " :End of /WHOIS";
@@ -333,70 +746,278 @@ fn whois(connection: &mut MysqlConnection, content: String, w_thread_id: i32) ->
     Ok(res)
 }
 
-/// Replying to WHOWAS commands, will reply no matter if user is logged in or not
-fn whowas(connection: &mut MysqlConnection, content: String, w_thread_id: i32) -> Result<Response, IrcError> {
-    let mut res = Response::new(":localhost ".to_string());
-
-    let sender = get_user_from_thread_id(connection, &w_thread_id).unwrap().nick;
+/// Replying to WHOWAS commands from the nick history, most recent sign-off first,
+///
+/// An optional count limits how many entries are sent for each nickname.
+fn whowas(connection: &mut dyn Storage, content: String, w_thread_id: i32) -> Result<Response, IrcError> {
+    // Expecting request in this form (RFC 2812):
+    // WHOWAS <nickname> *( "," <nickname> ) [ <count> [ <target> ] ]
+    let content_vec: Vec<&str> = content.split_whitespace().collect();
+
+    if content_vec.is_empty() {
+        return Err(NeedMoreParams);
+    }
 
-    match get_user_from_nick(connection, content.as_str()) {
-        Ok(user) => {
-            // if user has ever existed
-            res.content = res.content + "314 " + user.nick.as_str() + " " + user.nick.as_str() + " " + user.last_ip.as_str() + " " + user.real_name.as_str()
+    // a missing or non positive count means "every entry"
+    let count = content_vec.get(1).and_then(|count| count.parse::<i64>().ok()).unwrap_or(0);
+
+    let sender = state().user(w_thread_id).unwrap().nick.clone();
+    let mut lines: Vec<String> = Vec::new();
+
+    for target in content_vec[0].split(",") {
+        match connection.get_whowas(target, count) {
+            Ok(history) => {
+                for entry in history {
+                    // 314 "<nick> <user> <host> * :<real name>"
+                    lines.push(":localhost 314 ".to_string() + sender.as_str() + " " + entry.nick.as_str() + " " + entry.nick.as_str() + " " + format_host(entry.ip.as_str()).as_str() + " * :" + entry.real_name.as_str());
+                    // 312 "<nick> <server> :<server info>", server info being the sign-off time
+                    lines.push(":localhost 312 ".to_string() + sender.as_str() + " " + entry.nick.as_str() + " localhost :" + format_epoch(entry.signoff_time).as_str());
+                }
+            }
+            // Nickname has never signed off
+            Err(_) => lines.push(":localhost 406 ".to_string() + sender.as_str() + " " + target + " :There was no such nickname"),
         }
-        // User has never logged in
-        Err(_) => res.content = res.content + "406 " + sender.as_str() + " " + content.as_str() + " :No such nick registered",
-    }
 
-    res.content = res.content + "\n:localhost 369 " + sender.as_str() + " " + content.as_str() + " :End of /WHOWAS";
+        lines.push(":localhost 369 ".to_string() + sender.as_str() + " " + target + " :End of /WHOWAS");
+    }
 
-    Ok(res)
+    Ok(Response::new(lines.join("\n")))
 }
 
 // # Utility Functions #
 
+/// Completing registration of `session` once NICK and USER were received and CAP negotiation is over,
+///
+/// Password sent with PASS is checked if the server requires one, so is operator status if the server only accepts operators
+/// (OPER has to come before NICK and USER),
+/// user is logged in (see `rirc_state`) then the welcome burst is sent,
+/// does nothing while registration is incomplete.
+fn register(connection: &mut dyn Storage, session: &mut Session) -> Result<Response, IrcError> {
+    if session.registered || session.cap_negotiating {
+        return Ok(Response::no_response());
+    }
+
+    let (nick, real_name) = match (session.nick.clone(), session.real_name.clone()) {
+        (Some(nick), Some(real_name)) => (nick, real_name),
+        _ => return Ok(Response::no_response()),
+    };
+
+    // Server client connected to requires a password
+    if let Some(hash) = session.listener_password.clone() {
+        let password = session.password.clone().unwrap_or_default();
+
+        if ! bcrypt::verify(password, hash.as_str()).unwrap_or(false) {
+            return Err(PasswdMismatch);
+        }
+    }
+
+    // Server client connected to only accepts operators
+    if session.oper_only && ! session.op {
+        return Err(NoOperHost);
+    }
+
+    // Nick's account is protected by fingerprints, user has to be logged into it
+    if let Err(error) = check_account(connection, session, nick.as_str()) {
+        session.nick = Option::None;
+        return Err(error);
+    }
+
+    let user = LiveUser {
+        thread_id: session.thread_id,
+        nick: nick.clone(),
+        real_name: real_name.clone(),
+        ip: session.addr.clone(),
+        op: session.op,
+        // Users connected through TLS are +Z, operators are +o
+        modes: [(session.secure, 'Z'), (session.op, 'o')].iter().filter(|(set, _)| *set).map(|(_, mode)| mode).collect(),
+        channels: Vec::new(),
+    };
+
+    if let Err(error) = state().add_user(user.clone()) {
+        // Nickname has been taken since NICK was received
+        session.nick = Option::None;
+        return Err(error);
+    }
+
+    session.registered = true;
+
+    if connection.get_user_from_nick(nick.as_str()).is_err() {
+        session.created = Some(nick.clone());
+    }
+
+    // Account is claimed in database in the background
+    let (thread_id, addr) = (session.thread_id, session.addr.clone());
+    persist(move |connection| {
+        match connection.get_user_from_nick(nick.as_str()) {
+            // A user with same name has already logged in but logged off since then
+            Ok(_) => connection.edit_user(&get_current_epoch(), nick.as_str(), addr.as_str(), &true, &thread_id).unwrap(),
+            // Username has never logged in
+            Err(_) => connection.create_user(&get_current_epoch(), nick.as_str(), real_name.as_str(), addr.as_str(), &true, &false, &thread_id),
+        }
+
+        let account = connection.get_user_from_nick(nick.as_str()).unwrap();
+        connection.set_real_name(account, real_name.as_str());
+    });
+
+    let mut res = welcome(connection, session.thread_id);
+
+    if ! user.modes.is_empty() {
+        res = res + "\n:" + user.nick.as_str() + " MODE " + user.nick.as_str() + " :+" + user.modes.as_str();
+    }
+
+    Ok(Response::new(res))
+}
+
 /// Function used when clients call for unsupported commands
 fn unimplemented() -> Result<Response, IrcError> {
     Ok(Response::no_response())
 }
 
+/// Function building the RPL_MYINFO (004) line sent to `nick`, which always has its 4 parameters,
+///
+/// An empty parameter is sent as an empty trailing one (`:`), so user and channel modes can't be mistaken for each other.
+///
+/// Example: `myinfo("johndoe")` returns `:localhost 004 johndoe localhost rustyrc-0.1.0 oZ :`.
+fn myinfo(nick: &str) -> String {
+    let parameter = |modes: &str| if modes.is_empty() { ":".to_string() } else { modes.to_string() };
+
+    ":localhost 004 ".to_string() + nick + " localhost " + SERVER_VERSION + " " + parameter(USER_MODES).as_str() + " " + parameter(CHANNEL_MODES).as_str()
+}
+
+/// Function building the registration burst sent once user is logged in:
+/// - RPL_WELCOME, RPL_YOURHOST, RPL_CREATED, RPL_MYINFO (001 to 004),
+/// - RPL_ISUPPORT (005, see `isupport()`),
+/// - MOTD, or ERR_NOMOTD.
+fn welcome(connection: &mut dyn Storage, thread_id: i32) -> String {
+    let user = state().user(thread_id).unwrap().clone();
+    let nick = user.nick.as_str();
+    let network = connection.get_setting("name").map(|setting| setting.content).unwrap_or("RustyRC".to_string());
+
+    let mut lines = vec![
+        ":localhost 001 ".to_string() + nick + " :Welcome to the " + network.as_str() + " IRC Network " + create_user_line(&user, "").trim_start_matches(':').trim_end(),
+        ":localhost 002 ".to_string() + nick + " :Your host is localhost, running version " + SERVER_VERSION,
+        ":localhost 003 ".to_string() + nick + " :This server was created " + format_epoch(*START_TIME).as_str(),
+        myinfo(nick),
+    ];
+
+    // 005 lines are limited to 13 tokens each
+    for tokens in isupport(network.as_str()).chunks(13) {
+        lines.push(":localhost 005 ".to_string() + nick + " " + tokens.join(" ").as_str() + " :are supported by this server");
+    }
+
+    match motd(connection, thread_id) {
+        Ok(res) => lines.push(res.content),
+        Err(error) => lines.push(Response::from_error(error).content),
+    }
+
+    lines.join("\n")
+}
+
+/// Function returning ISUPPORT tokens (005) for `network`, built from the limits the server really enforces.
+fn isupport(network: &str) -> Vec<String> {
+    vec![
+        "CHANTYPES=#".to_string(),
+        // no channel user modes (no @ or +) and no channel modes for now
+        "PREFIX=".to_string(),
+        "CHANMODES=,,,".to_string(),
+        "NICKLEN=".to_string() + NICKLEN.to_string().as_str(),
+        "CHANNELLEN=".to_string() + CHANNELLEN.to_string().as_str(),
+        "TOPICLEN=".to_string() + TOPICLEN.to_string().as_str(),
+        "NETWORK=".to_string() + network.replace(" ", "_").as_str(),
+        // nicks are ASCII alphanumeric, only ASCII letters are compared case insensitively
+        "CASEMAPPING=ascii".to_string(),
+        "TARGMAX=JOIN:1,NAMES:1,PART:1,PRIVMSG:1,WHOIS:1,WHOWAS:".to_string(),
+    ]
+}
+
 /// Function used to create a user line when user is leaving/joining channel/server or sending a message, in the form:
 ///
 /// `:<nickname>!<nickname>@<last_ip> <content>`
-fn create_user_line(user: User, content: &str) -> String {
-    let nick = user.nick;
-    let last_ip = user.last_ip;
+fn create_user_line(user: &LiveUser, content: &str) -> String {
+    let nick = &user.nick;
+    let last_ip = &user.ip;
 
     return ":".to_string() + nick.as_str() + "!" + nick.as_str() + "@" + last_ip.as_str() + " " + content
 }
 
 /// Checking if a nickname is valid,
-/// - Less than 11 chars,
+/// - At most `NICKLEN` (11) chars,
 /// - Not banned,
-/// - Does not contain special characters (even `_` are banned).
-fn check_nick(connection: &mut MysqlConnection, nick: &str) -> Result<(), IrcError> {
+/// - Does not contain special characters (even `_` are banned) or non-ASCII letters.
+fn check_nick(nick: &str) -> Result<(), IrcError> {
     // Is username banned ?
-    if get_ban(connection, &false, nick).is_ok() {
+    if state().is_banned(false, nick) {
         return Err(YoureBannedCreep);
     }
 
     // Is username longer than 11 characters ?
-    if nick.len() > 11 {
+    if nick.chars().count() > NICKLEN {
         return Err(ErroneusNickname);
     }
 
-    // Is username made of alphanumeric characters ?
-    if ! nick.chars().all(char::is_alphanumeric) {
+    // Is username made of ASCII alphanumeric characters ? (see `CASEMAPPING` in `isupport()`)
+    if ! nick.chars().all(|character| character.is_ascii_alphanumeric()) {
         return Err(ErroneusNickname);
     }
 
     return Ok(());
 }
 
+/// Returns `true` if the account of `nick` has fingerprints attached, only users logged into it may then use `nick`.
+fn is_protected(connection: &mut dyn Storage, nick: &str) -> bool {
+    connection.get_user_from_nick(nick).is_ok_and(|account| ! connection.get_user_certfps(account).is_empty())
+}
+
+/// Checking if `session` may use `nick`, ERR_NICKLOCKED if its account is protected (see `is_protected()`)
+/// and user is not logged into it.
+fn check_account(connection: &mut dyn Storage, session: &Session, nick: &str) -> Result<(), IrcError> {
+    if session.account.as_ref().is_some_and(|account| account.eq_ignore_ascii_case(nick)) {
+        return Ok(());
+    }
+
+    if is_protected(connection, nick) {
+        return Err(NickLocked);
+    }
+
+    Ok(())
+}
+
 /// Checking if user is banned, returns a `bool`.
-fn is_banned(connection: &mut MysqlConnection, addr: &str) -> bool {
-    return match get_ban(connection, &true, addr) {
-        Ok(_) => true,
-        Err(_) => false
+fn is_banned(addr: &str) -> bool {
+    state().is_banned(true, addr)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isupport_advertises_enforced_limits() {
+        let tokens = isupport("Company Chat");
+
+        assert!(tokens.contains(&("NICKLEN=".to_string() + NICKLEN.to_string().as_str())));
+        assert!(tokens.contains(&("CHANNELLEN=".to_string() + CHANNELLEN.to_string().as_str())));
+        assert!(tokens.contains(&"CASEMAPPING=ascii".to_string()));
+        assert!(tokens.contains(&"NETWORK=Company_Chat".to_string()));
+        // Each token is a single word, 005 lines hold at most 13 of them
+        assert!(tokens.iter().all(|token| ! token.is_empty() && ! token.contains(' ')));
+        assert!(tokens.len() <= 13);
+    }
+
+    #[test]
+    fn myinfo_always_sends_channel_modes() {
+        let line = myinfo("johndoe");
+        let parameters: Vec<&str> = line.split(' ').skip(2).collect();
+
+        assert_eq!(parameters, vec!["johndoe", "localhost", SERVER_VERSION, USER_MODES, if CHANNEL_MODES.is_empty() { ":" } else { CHANNEL_MODES }]);
+    }
+
+    #[test]
+    fn check_nick_only_accepts_ascii_alphanumeric() {
+        assert!(check_nick("johndoe42").is_ok());
+        assert!(check_nick("JohnDoe").is_ok());
+        assert!(check_nick("john_doe").is_err());
+        assert!(check_nick("jöhn").is_err());
+        assert!(check_nick("ｊｏｈｎ").is_err());
+        assert!(check_nick("averyveryverylongnick").is_err());
     }
-}
\ No newline at end of file
+}
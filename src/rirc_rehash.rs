@@ -0,0 +1,161 @@
+//! # RustyRC Rehash
+//!
+//! File containing what's reloaded without restarting the server, by operators (REHASH) or with SIGHUP.
+//!
+//! Most settings are read whenever they are used (MOTD, timeouts, flood control...),
+//! `rehash()` reads the configuration file again (see `rirc_config`), checks settings,
+//! and reloads what's only read at startup: connection limits, TLS certificate, log level (unless `RUST_LOG` is set),
+//! registered channels and bans. Listeners (addresses, ports, passwords) and storage still need a restart.
+//!
+//! New configuration is read and checked first, nothing is changed unless all of it is valid.
+
+use std::fs;
+use std::sync::{Arc, OnceLock};
+use ipnet::IpNet;
+use log::{info, warn};
+use rustls::ServerConfig;
+use crate::rirc_config::{config, load_config, log_level, set_config};
+use crate::rirc_limits::ConnectionLimits;
+use crate::rirc_state::state;
+use crate::rirc_storage::Storage;
+use crate::rirc_stream::{load_tls_config, TlsConfig};
+
+/// Settings holding a number (seconds, milliseconds or counts), checked by `rehash()`.
+const NUMBER_SETTINGS: [&str; 13] = [
+    "reg_timeout", "ping_idle", "pong_wait", "nick_delay", "flood_delay", "flood_burst", "flood_max",
+    "max_clients", "max_per_ip", "reconn_max", "reconn_time", "db_threads", "grace_time",
+];
+
+/// What `rehash()` replaces, shared with listeners by `main()`.
+struct Reloadable {
+    limits: ConnectionLimits,
+    tls: Option<TlsConfig>, // only set if a listener uses TLS
+}
+
+static RELOADABLE: OnceLock<Reloadable> = OnceLock::new();
+
+/// Public function making `limits` and `tls` (used by listeners) replaced by `rehash()`, called once at startup.
+pub fn register(limits: ConnectionLimits, tls: Option<TlsConfig>) {
+    RELOADABLE.set(Reloadable { limits, tls }).ok();
+}
+
+/// Public function loading TLS certificate from `tls_cert` and `tls_key` settings, `None` if they are not set,
+///
+/// Clients are asked for a certificate (CertFP) if `tls_certfp` setting is 1.
+///
+/// Example:
+/// ```rust
+/// let config = load_tls(connection).unwrap().map(TlsConfig::new);
+/// ```
+pub fn load_tls(connection: &mut dyn Storage) -> Result<Option<Arc<ServerConfig>>, String> {
+    tls_from(|key| connection.get_setting(key).ok().map(|setting| setting.content))
+}
+
+/// Loads TLS certificate from settings returned by `setting`, see `load_tls()`.
+fn tls_from(mut setting: impl FnMut(&str) -> Option<String>) -> Result<Option<Arc<ServerConfig>>, String> {
+    let (tls_cert, tls_key) = match (setting("tls_cert"), setting("tls_key")) {
+        (Some(tls_cert), Some(tls_key)) => (tls_cert, tls_key),
+        _ => return Ok(Option::None),
+    };
+
+    let request_client_cert = setting("tls_certfp").is_some_and(|content| content.trim() == "1");
+
+    load_tls_config(tls_cert.trim(), tls_key.trim(), request_client_cert)
+        .map(Some)
+        .map_err(|error| "Could not load TLS certificate: ".to_string() + error.as_str())
+}
+
+/// Public function reloading configuration from database, returns every error found if it's not valid,
+/// in which case nothing is changed.
+///
+/// Example:
+/// ```rust
+/// if let Err(errors) = rehash(connection) {
+///     println!("{}", errors.join(", "));
+/// }
+/// ```
+pub fn rehash(connection: &mut dyn Storage) -> Result<(), Vec<String>> {
+    let reloadable = RELOADABLE.get().ok_or(vec!["Server is still starting".to_string()])?;
+    let mut errors: Vec<String> = Vec::new();
+
+    // New file is only checked here, it's used once everything is valid
+    let candidate = match load_config() {
+        Ok(candidate) => Arc::new(candidate),
+        Err(file_errors) => {
+            errors.extend(file_errors);
+            config()
+        }
+    };
+
+    // Rows of the settings table override the new file, like they override the current one (see `get_setting()`)
+    let mut setting = |key: &str| match connection.get_setting(key) {
+        Ok(setting) if setting.id != 0 => Some(setting.content),
+        _ => candidate.setting(key),
+    };
+
+    for key in NUMBER_SETTINGS {
+        if let Some(content) = setting(key) {
+            if content.trim().parse::<u64>().is_err() {
+                errors.push(key.to_string() + ": not a number (" + content.trim() + ")");
+            }
+        }
+    }
+
+    if let Some(content) = setting("clone_exempt") {
+        for range in content.split(",").map(str::trim).filter(|range| ! range.is_empty()) {
+            if range.parse::<IpNet>().is_err() {
+                errors.push("clone_exempt: invalid CIDR range (".to_string() + range + ")");
+            }
+        }
+    }
+
+    if let Some(content) = setting("motd_file") {
+        if let Err(error) = fs::metadata(content.trim()) {
+            errors.push("motd_file: ".to_string() + content.trim() + ": " + error.to_string().as_str());
+        }
+    }
+
+    // TLS listeners can't be left without a certificate
+    let tls = match (&reloadable.tls, tls_from(&mut setting)) {
+        (Some(_), Ok(Some(config))) => Some(config),
+        (Some(_), Ok(Option::None)) => {
+            errors.push("tls_cert, tls_key: not set, TLS listeners need them".to_string());
+            Option::None
+        }
+        (Some(_), Err(error)) => {
+            errors.push(error);
+            Option::None
+        }
+        (Option::None, _) => Option::None,
+    };
+
+    let channels = connection.get_all_channels().unwrap_or_else(|error| {
+        errors.push(format!("Could not read channels: {:?}", error));
+        Vec::new()
+    });
+    let bans = connection.get_all_bans();
+
+    if ! errors.is_empty() {
+        for error in errors.iter() {
+            warn!("Rehash: {}", error);
+        }
+        warn!("Rehash failed, configuration unchanged");
+
+        return Err(errors);
+    }
+
+    // Everything was read and checked, it's applied at once
+    set_config(candidate);
+    reloadable.limits.replace(&ConnectionLimits::from_settings(connection));
+    if let (Some(current), Some(config)) = (&reloadable.tls, tls) {
+        current.replace(config);
+    }
+    state().reload(channels, bans);
+    if let Some(level) = log_level() {
+        log::set_max_level(level);
+    }
+
+    info!("Configuration reloaded");
+
+    Ok(())
+}
@@ -8,6 +8,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    certfps (id) {
+        id -> Integer,
+        id_user -> Integer,
+        fingerprint -> Char,
+    }
+}
+
 diesel::table! {
     channels (id) {
         id -> Integer,
@@ -20,17 +28,20 @@ diesel::table! {
 }
 
 diesel::table! {
-    memberships (id) {
+    listeners (id) {
         id -> Integer,
-        id_user -> Integer,
-        id_channel -> Integer,
+        address -> Char,
+        port -> Integer,
+        transport -> Char,
+        password -> Char,
+        oper_only -> Bool,
     }
 }
 
 diesel::table! {
     settings (id) {
         id -> Integer,
-        key -> Char,
+        key -> Varchar,
         content -> Text,
     }
 }
@@ -48,10 +59,22 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    whowas (id) {
+        id -> Integer,
+        nick -> Char,
+        real_name -> Char,
+        ip -> Char,
+        signoff_time -> Bigint,
+    }
+}
+
 diesel::allow_tables_to_appear_in_same_query!(
     bans,
+    certfps,
     channels,
-    memberships,
+    listeners,
     settings,
     users,
+    whowas,
 );
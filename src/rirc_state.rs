@@ -0,0 +1,298 @@
+//! # RustyIRC State
+//!
+//! File containing the live state of the server, held in memory as the source of truth:
+//! users logged in (by `thread_id`), their nicks and modes, channels and their members.
+//!
+//! Durable data (registered channels, bans) is loaded from database at startup,
+//! changes to durable data (accounts, channels, bans, nick history) are written to database in the background by `persist()`,
+//! so commands like JOIN, NAMES or PRIVMSG never wait for the database.
+
+use std::collections::{HashMap, HashSet};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+use log::error;
+use crate::rirc_lib::*;
+use crate::rirc_lib::IrcError::*;
+use crate::rirc_storage::{establish_connection, Storage};
+
+/// A user logged in, from registration until its connection is closed.
+#[derive(Clone)]
+pub struct LiveUser {
+    pub thread_id: i32,
+    pub nick: String,
+    pub real_name: String,
+    pub ip: String,
+    pub op: bool,
+    pub modes: String, // user modes, e.g. `Z` when connected through TLS
+    pub channels: Vec<String>, // names of channels user is a member of
+}
+
+impl LiveUser {
+    /// Returns user as stored in database (account), for writes done by `persist()`.
+    pub fn to_user(&self) -> User {
+        User {
+            id: 0,
+            last_login: get_current_epoch(),
+            nick: self.nick.clone(),
+            real_name: self.real_name.clone(),
+            last_ip: self.ip.clone(),
+            is_connected: true,
+            op: self.op,
+            thread_id: self.thread_id,
+        }
+    }
+}
+
+/// A registered channel, with its current members.
+#[derive(Clone)]
+pub struct LiveChannel {
+    pub id: i32,
+    pub name: String,
+    pub topic: String,
+    pub members: Vec<i32>, // `thread_id` of members, in the order they joined
+}
+
+/// Live state of the server, see `state()`,
+///
+/// Nicks and channel names are compared case insensitively, as they are by the database,
+/// only ASCII letters are folded (`CASEMAPPING=ascii`, see `isupport()`).
+#[derive(Default)]
+pub struct State {
+    users: HashMap<i32, LiveUser>,
+    nicks: HashMap<String, i32>, // ASCII lowercase nick to `thread_id`
+    channels: HashMap<String, LiveChannel>, // ASCII lowercase name to channel
+    bans: HashSet<(bool, String)>, // `is_ip` and ASCII lowercase content
+}
+
+/// Live state of the server, shared by every connection.
+static STATE: LazyLock<Mutex<State>> = LazyLock::new(|| Mutex::new(State::default()));
+
+/// Public function locking the live state of the server,
+///
+/// Lock is held until the guard is dropped, lines should be sent (see `send_to()`) once it is,
+/// a request that panicked while holding it doesn't make it unusable for everyone else.
+///
+/// Example:
+/// ```rust
+/// let nick = state().user(12).map(|user| user.nick.clone());
+/// ```
+pub fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl State {
+    /// Loads registered channels and bans from database, used at startup.
+    pub fn load(&mut self, connection: &mut dyn Storage) {
+        self.reload(connection.get_all_channels().unwrap_or_default(), connection.get_all_bans());
+    }
+
+    /// Replaces registered channels and bans (REHASH, see `rirc_rehash`),
+    ///
+    /// Channels still registered keep their members, members of channels no longer registered leave them silently.
+    pub fn reload(&mut self, channels: Vec<Channel>, bans: Vec<Ban>) {
+        let mut live: HashMap<String, LiveChannel> = HashMap::new();
+
+        for channel in channels {
+            let members = self.channels.remove(&channel.name.to_ascii_lowercase())
+                .map(|previous| previous.members)
+                .unwrap_or_default();
+
+            live.insert(channel.name.to_ascii_lowercase(), LiveChannel {
+                id: channel.id,
+                name: channel.name,
+                topic: channel.topic,
+                members,
+            });
+        }
+
+        // Channels left were removed from database
+        for channel in self.channels.values() {
+            for member in channel.members.iter() {
+                if let Some(user) = self.users.get_mut(member) {
+                    user.channels.retain(|joined| ! joined.eq_ignore_ascii_case(channel.name.as_str()));
+                }
+            }
+        }
+
+        self.channels = live;
+        self.bans = bans.into_iter().map(|ban| (ban.is_ip, ban.content.to_ascii_lowercase())).collect();
+    }
+
+    /// Returns user of `thread_id`, if logged in.
+    pub fn user(&self, thread_id: i32) -> Option<&LiveUser> {
+        self.users.get(&thread_id)
+    }
+
+    /// Returns user of `thread_id` to be changed, if logged in.
+    pub fn user_mut(&mut self, thread_id: i32) -> Option<&mut LiveUser> {
+        self.users.get_mut(&thread_id)
+    }
+
+    /// Returns user logged in as `nick`, if any.
+    pub fn user_from_nick(&self, nick: &str) -> Option<&LiveUser> {
+        self.nicks.get(&nick.to_ascii_lowercase()).and_then(|thread_id| self.users.get(thread_id))
+    }
+
+    /// Logs `user` in, ERR_NICKNAMEINUSE if its nick is already taken.
+    pub fn add_user(&mut self, user: LiveUser) -> Result<(), IrcError> {
+        if self.nicks.contains_key(&user.nick.to_ascii_lowercase()) {
+            return Err(NicknameInUse);
+        }
+
+        self.nicks.insert(user.nick.to_ascii_lowercase(), user.thread_id);
+        self.users.insert(user.thread_id, user);
+
+        Ok(())
+    }
+
+    /// Changes nick of user of `thread_id`, ERR_NICKNAMEINUSE if it's taken by someone else (user may only change case).
+    pub fn rename_user(&mut self, thread_id: i32, nick: &str) -> Result<(), IrcError> {
+        if self.nicks.get(&nick.to_ascii_lowercase()).is_some_and(|owner| *owner != thread_id) {
+            return Err(NicknameInUse);
+        }
+
+        if let Some(user) = self.users.get_mut(&thread_id) {
+            self.nicks.remove(&user.nick.to_ascii_lowercase());
+            self.nicks.insert(nick.to_ascii_lowercase(), thread_id);
+            user.nick = nick.to_string();
+        }
+
+        Ok(())
+    }
+
+    /// Logs user of `thread_id` off, it leaves all its channels.
+    pub fn remove_user(&mut self, thread_id: i32) -> Option<LiveUser> {
+        let user = self.users.remove(&thread_id)?;
+        self.nicks.remove(&user.nick.to_ascii_lowercase());
+
+        for name in user.channels.iter() {
+            if let Some(channel) = self.channels.get_mut(&name.to_ascii_lowercase()) {
+                channel.members.retain(|member| *member != thread_id);
+            }
+        }
+
+        Some(user)
+    }
+
+    /// Returns channel called `name`, if registered.
+    pub fn channel(&self, name: &str) -> Option<&LiveChannel> {
+        self.channels.get(&name.to_ascii_lowercase())
+    }
+
+    /// Returns every registered channel, in the order they were created.
+    pub fn channels(&self) -> Vec<&LiveChannel> {
+        let mut channels: Vec<&LiveChannel> = self.channels.values().collect();
+        channels.sort_by_key(|channel| channel.id);
+
+        channels
+    }
+
+    /// Makes user of `thread_id` a member of channel `name`, ERR_NOSUCHCHANNEL if it's not registered.
+    pub fn join(&mut self, thread_id: i32, name: &str) -> Result<&LiveChannel, IrcError> {
+        let channel = self.channels.get_mut(&name.to_ascii_lowercase()).ok_or(NoSuchChannel)?;
+        let user = self.users.get_mut(&thread_id).ok_or(NotRegistered)?;
+
+        if ! channel.members.contains(&thread_id) {
+            channel.members.push(thread_id);
+            user.channels.push(channel.name.clone());
+        }
+
+        Ok(channel)
+    }
+
+    /// Removes user of `thread_id` from channel `name`, ERR_NOSUCHCHANNEL or ERR_NOTONCHANNEL if it can't.
+    pub fn part(&mut self, thread_id: i32, name: &str) -> Result<(), IrcError> {
+        let channel = self.channels.get_mut(&name.to_ascii_lowercase()).ok_or(NoSuchChannel)?;
+
+        if ! channel.members.contains(&thread_id) {
+            return Err(NotOnChannel);
+        }
+
+        channel.members.retain(|member| *member != thread_id);
+
+        if let Some(user) = self.users.get_mut(&thread_id) {
+            user.channels.retain(|joined| ! joined.eq_ignore_ascii_case(name));
+        }
+
+        Ok(())
+    }
+
+    /// Returns nick of every member of `channel`, in the order they joined.
+    pub fn member_nicks(&self, channel: &LiveChannel) -> Vec<String> {
+        channel.members.iter()
+            .filter_map(|member| self.users.get(member))
+            .map(|user| user.nick.clone())
+            .collect()
+    }
+
+    /// Returns `thread_id` of every user sharing at least one channel with user of `thread_id`,
+    ///
+    /// Each of them is returned once, user of `thread_id` is not part of them.
+    pub fn neighbours(&self, thread_id: i32) -> Vec<i32> {
+        let mut neighbours: Vec<i32> = Vec::new();
+
+        for name in self.users.get(&thread_id).map(|user| user.channels.clone()).unwrap_or_default() {
+            for member in self.channel(name.as_str()).map(|channel| channel.members.clone()).unwrap_or_default() {
+                if member != thread_id && ! neighbours.contains(&member) {
+                    neighbours.push(member);
+                }
+            }
+        }
+
+        neighbours
+    }
+
+    /// Returns `true` if `content` (an IP if `is_ip`, a nick otherwise) is banned.
+    pub fn is_banned(&self, is_ip: bool, content: &str) -> bool {
+        self.bans.contains(&(is_ip, content.to_ascii_lowercase()))
+    }
+}
+
+/// Write to database queued by `persist()`.
+type Write = Box<dyn FnOnce(&mut dyn Storage) + Send>;
+
+/// Queue of writes to database, run in order by their own thread.
+static WRITES: LazyLock<Sender<Write>> = LazyLock::new(|| {
+    let (writes, queue) = channel::<Write>();
+
+    thread::Builder::new()
+        .name("persist".to_string())
+        .spawn(move || {
+            while let Ok(write) = queue.recv() {
+                // Writes queued meanwhile share the same connection
+                let mut connection = establish_connection();
+
+                for write in std::iter::once(write).chain(queue.try_iter()) {
+                    if catch_unwind(AssertUnwindSafe(|| write(&mut *connection))).is_err() {
+                        error!("Could not write to database, change is lost");
+                    }
+                }
+            }
+        })
+        .expect("Could not start database writer");
+
+    writes
+});
+
+/// Public function queuing `write` to be run on database in the background, writes are run in the order they were queued,
+///
+/// Example:
+/// ```rust
+/// let user = state().user(12).unwrap().to_user();
+/// persist(move |connection| connection.create_whowas(user));
+/// ```
+pub fn persist(write: impl FnOnce(&mut dyn Storage) + Send + 'static) {
+    WRITES.send(Box::new(write)).ok();
+}
+
+/// Public function waiting up to `timeout` for every write queued by `persist()` so far to be run,
+/// returns `false` if they were not (used at shutdown).
+pub fn flush(timeout: Duration) -> bool {
+    let (done, wait) = channel::<()>();
+    persist(move |_| { done.send(()).ok(); });
+
+    wait.recv_timeout(timeout).is_ok()
+}
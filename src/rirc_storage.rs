@@ -0,0 +1,725 @@
+//! # RustyIRC Storage
+//!
+//! File containing the `Storage` trait, through which every other module reaches the database,
+//! and its implementation with Diesel.
+//!
+//! Backend is chosen at build time with a cargo feature, only one of them can be enabled:
+//! - `mysql` (default): MySQL/MariaDB, migrations in `migrations/mysql`,
+//! - `postgres`: PostgreSQL, migrations in `migrations/postgres`,
+//! - `sqlite`: SQLite (bundled, no server needed), migrations in `migrations/sqlite`.
+//!
+//! Migrations of the chosen backend are embedded in the binary, and applied at startup (see `run_migrations()`).
+//!
+//! Nicks, channel names and setting keys are compared case insensitively by every backend
+//! (collation of their columns), as IRC expects.
+
+use std::env;
+use std::thread::sleep;
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+use diesel::prelude::*;
+use diesel::migration::MigrationSource;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use dotenvy::dotenv;
+use log::warn;
+use tokio::task::{spawn_blocking, JoinError};
+use crate::rirc_config::config;
+use crate::rirc_lib::*;
+use crate::rirc_lib::Error::*;
+use crate::rirc_schema::*;
+
+#[cfg(not(any(feature = "mysql", feature = "postgres", feature = "sqlite")))]
+compile_error!("A storage backend is needed, enable one of `mysql`, `postgres` or `sqlite` features.");
+
+#[cfg(any(all(feature = "mysql", feature = "postgres"), all(feature = "mysql", feature = "sqlite"), all(feature = "postgres", feature = "sqlite")))]
+compile_error!("Only one storage backend can be enabled, use `--no-default-features` with `--features postgres` or `--features sqlite`.");
+
+/// Connection to the backend selected by cargo features.
+#[cfg(feature = "mysql")]
+pub type DbConnection = diesel::MysqlConnection;
+
+/// Connection to the backend selected by cargo features.
+#[cfg(feature = "postgres")]
+pub type DbConnection = diesel::PgConnection;
+
+/// Connection to the backend selected by cargo features.
+#[cfg(feature = "sqlite")]
+pub type DbConnection = diesel::SqliteConnection;
+
+/// Public trait covering everything stored in database: users (and their nick history and certificates),
+/// channels, bans, settings and listeners (channel memberships only live in memory, see `rirc_state`),
+///
+/// It is implemented by `DbConnection` for the backend chosen at build time,
+/// functions handling clients only ask for a `&mut dyn Storage`.
+///
+/// Example:
+/// ```rust
+/// let connection = &mut establish_connection();
+/// let user = connection.get_user_from_nick("johndoe");
+/// ```
+pub trait Storage {
+    // Users
+
+    /// Returns a `User` when given its `nick`,
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.get_user_from_nick("johndoe");
+    /// ```
+    fn get_user_from_nick(&mut self, w_nick: &str) -> Result<User, Error>;
+
+    /// Creates a user,
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.create_user(&1674587646, "johndoe", "John Doe", "1.2.3.4", &true, &false, &4);
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    fn create_user(&mut self, w_last_login: &i64, w_nick: &str, w_real_name: &str,
+                   w_last_ip: &str, w_is_connected: &bool, w_op: &bool, w_thread_id: &i32);
+
+    /// Edits certain parts of an existing user from its nick,
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.edit_user(&1674587646, "johndoe", "1.1.1.1", &true, &4);
+    /// ```
+    fn edit_user(&mut self, w_last_login: &i64, w_nick: &str, w_last_ip: &str,
+                 w_is_connected: &bool, w_thread_id: &i32) -> Result<(), Error>;
+
+    /// Sets `is_connected` of `user` to `w_is_connected`, its `thread_id` is reset to -1 when logging off.
+    fn set_connected(&mut self, user: User, w_is_connected: &bool);
+
+    /// Sets `nick` of `user` to `w_nick`,
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.set_nick(user, "JohnDoe");
+    /// ```
+    fn set_nick(&mut self, user: User, w_nick: &str);
+
+    /// Sets `real_name` of `user` to `w_real_name`,
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.set_real_name(user, "John Doe");
+    /// ```
+    fn set_real_name(&mut self, user: User, w_real_name: &str);
+
+    /// Sets every user logged off with a `thread_id` of -1 (used at startup).
+    fn clean_database(&mut self);
+
+    // Nick history
+
+    /// Stores a snapshot of `user` in the nick history, signed off now,
+    ///
+    /// Example:
+    /// ```rust
+    /// let user = connection.get_user_from_nick("johndoe").unwrap();
+    /// connection.create_whowas(user);
+    /// ```
+    fn create_whowas(&mut self, user: User);
+
+    /// Returns nick history of `w_nick`, most recent first,
+    ///
+    /// At most `w_count` entries are returned, or all of them if `w_count` is not positive.
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.get_whowas("johndoe", 5);
+    /// ```
+    fn get_whowas(&mut self, w_nick: &str, w_count: i64) -> Result<Vec<Whowas>, Error>;
+
+    // Client certificates
+
+    /// Returns the `User` `w_fingerprint` is attached to,
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.get_user_from_certfp("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08");
+    /// ```
+    fn get_user_from_certfp(&mut self, w_fingerprint: &str) -> Result<User, Error>;
+
+    /// Returns every fingerprint attached to `user`.
+    fn get_user_certfps(&mut self, user: User) -> Vec<Certfp>;
+
+    /// Attaches `w_fingerprint` to `user`.
+    fn create_certfp(&mut self, user: User, w_fingerprint: &str);
+
+    /// Detaches `w_fingerprint` from `user`, returns `true` if it was attached.
+    fn delete_certfp(&mut self, user: User, w_fingerprint: &str) -> bool;
+
+    // Bans
+
+    /// Returns every `Ban`.
+    fn get_all_bans(&mut self) -> Vec<Ban>;
+
+    // Channels
+
+    /// Returns every `Channel`.
+    fn get_all_channels(&mut self) -> Result<Vec<Channel>, Error>;
+
+    // Settings
+
+    /// Returns a `Setting` when given its `key`, from the `settings` table or else from the configuration file
+    /// (see `Config::setting()`, its `id` is then 0),
+    ///
+    /// Example:
+    /// ```rust
+    /// connection.get_setting("name");
+    /// ```
+    fn get_setting(&mut self, w_key: &str) -> Result<Setting, Error>;
+
+    /// Returns every configured `Listener`, from the `listeners` table or else from `[[listeners]]` of the configuration file.
+    fn get_all_listeners(&mut self) -> Vec<Listener>;
+}
+
+/// Database URL given on the command line (`--database-url`), it wins over `DATABASE_URL` and `[storage]`.
+static DATABASE_URL: OnceLock<String> = OnceLock::new();
+
+/// Public function making `url` the database URL, whatever `DATABASE_URL` or `[storage]` say, it has to be called before
+/// the first `establish_connection()`.
+pub fn set_database_url(url: String) {
+    DATABASE_URL.set(url).ok();
+}
+
+/// Public function returning the database URL: `--database-url`, `DATABASE_URL`, or `url` in `[storage]`, if any is set.
+pub fn database_url() -> Option<String> {
+    dotenv().ok();
+
+    DATABASE_URL.get().cloned()
+        .or(env::var("DATABASE_URL").ok())
+        .or(config().storage.url.clone())
+}
+
+/// Pool of database connections, see `establish_connection()`,
+///
+/// - `--database-url`, `DATABASE_URL`, or `url` in `[storage]` of the configuration file,
+/// - `DATABASE_POOL_SIZE`, or `pool_size`: connections kept at most, 10 by default,
+/// - `DATABASE_TIMEOUT`, or `timeout`: seconds to wait for a free connection, 5 by default.
+///
+/// Connections are checked before being handed out, so the ones broken by a database restart are replaced.
+static POOL: LazyLock<Pool<ConnectionManager<DbConnection>>> = LazyLock::new(|| {
+    dotenv().ok();
+
+    let storage = config().storage.clone();
+
+    let database_url = database_url().expect("DATABASE_URL or `url` in [storage] must be set");
+
+    let size = env::var("DATABASE_POOL_SIZE").ok()
+        .and_then(|size| size.trim().parse().ok())
+        .or(storage.pool_size)
+        .unwrap_or(10);
+
+    let timeout = env::var("DATABASE_TIMEOUT").ok()
+        .and_then(|timeout| timeout.trim().parse().ok())
+        .or(storage.timeout)
+        .unwrap_or(5);
+
+    let builder = Pool::builder()
+        .max_size(size)
+        .connection_timeout(Duration::from_secs(timeout))
+        .test_on_check_out(true);
+
+    #[cfg(feature = "sqlite")]
+    let builder = builder.connection_customizer(Box::new(SqlitePragmas));
+
+    // Connections are established when needed, so the server can start before the database
+    builder.build_unchecked(ConnectionManager::new(database_url))
+});
+
+/// Migrations of the backend chosen at build time, embedded in the binary.
+#[cfg(feature = "mysql")]
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/mysql");
+
+/// Migrations of the backend chosen at build time, embedded in the binary.
+#[cfg(feature = "postgres")]
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+
+/// Migrations of the backend chosen at build time, embedded in the binary.
+#[cfg(feature = "sqlite")]
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+
+/// Versions recorded by databases set up before migrations were timestamped, and what they became.
+const LEGACY_VERSIONS: [(&str, &str); 1] = [("init", "20240101000001")];
+
+/// Public function applying migrations not applied yet to the database, returns their versions,
+///
+/// It refuses to touch a database with migrations this binary doesn't know (schema newer than the binary).
+///
+/// Example:
+/// ```rust
+/// let applied = run_migrations(&mut establish_connection()).unwrap();
+/// ```
+pub fn run_migrations(connection: &mut DbConnection) -> Result<Vec<String>, String> {
+    // Renaming versions of old databases, so their migrations are not applied twice
+    for (legacy, version) in LEGACY_VERSIONS {
+        diesel::sql_query("UPDATE __diesel_schema_migrations SET version = '".to_string() + version + "' WHERE version = '" + legacy + "'")
+            .execute(connection)
+            .ok();
+    }
+
+    let known: Vec<String> = MigrationSource::<<DbConnection as Connection>::Backend>::migrations(&MIGRATIONS)
+        .map_err(|error| error.to_string())?
+        .iter()
+        .map(|migration| migration.name().version().to_string())
+        .collect();
+
+    let unknown: Vec<String> = connection.applied_migrations()
+        .map_err(|error| error.to_string())?
+        .iter()
+        .map(|version| version.to_string())
+        .filter(|version| ! known.contains(version))
+        .collect();
+
+    if ! unknown.is_empty() {
+        return Err("Database schema is newer than this binary (unknown migrations: ".to_string() + unknown.join(", ").as_str() + ")");
+    }
+
+    connection.run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(|version| version.to_string()).collect())
+        .map_err(|error| error.to_string())
+}
+
+/// SQLite connections wait for each other instead of failing with `database is locked`,
+/// and readers don't block the writer (WAL journal).
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqlitePragmas;
+
+#[cfg(feature = "sqlite")]
+impl diesel::r2d2::CustomizeConnection<DbConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, connection: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::connection::SimpleConnection;
+
+        connection.batch_execute("PRAGMA busy_timeout = 5000; PRAGMA journal_mode = WAL;")
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Longest time `establish_connection()` waits between two attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Public function that hands out a database connection from the pool (using `DATABASE_URL`),
+/// it goes back to the pool once dropped,
+///
+/// While the database is unreachable, it retries with a growing delay (up to 30 seconds) instead of panicking.
+///
+/// Example:
+/// ```rust
+/// let connection = &mut establish_connection();
+/// let ip: Setting = connection.get_setting("ip");
+/// ```
+pub fn establish_connection() -> PooledConnection<ConnectionManager<DbConnection>> {
+    let mut delay = Duration::from_millis(500);
+
+    loop {
+        match POOL.get() {
+            Ok(connection) => return connection,
+            Err(error) => {
+                warn!("Could not get a database connection ({}), retrying in {:?}", error, delay);
+                sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/// Database connection taken from the pool on first use only, see `db()`,
+///
+/// So requests answered from memory (see `rirc_state`) don't wait for the database.
+pub struct LazyConnection {
+    connection: Option<PooledConnection<ConnectionManager<DbConnection>>>,
+}
+
+impl LazyConnection {
+    /// Create a `LazyConnection`, no connection is taken yet.
+    pub fn new() -> LazyConnection {
+        LazyConnection { connection: Option::None }
+    }
+
+    /// Returns the connection, taken from the pool (see `establish_connection()`) if it's the first use.
+    fn get(&mut self) -> &mut DbConnection {
+        self.connection.get_or_insert_with(establish_connection)
+    }
+}
+
+impl Storage for LazyConnection {
+    fn get_user_from_nick(&mut self, w_nick: &str) -> Result<User, Error> {
+        self.get().get_user_from_nick(w_nick)
+    }
+
+    fn create_user(&mut self, w_last_login: &i64, w_nick: &str, w_real_name: &str,
+                   w_last_ip: &str, w_is_connected: &bool, w_op: &bool, w_thread_id: &i32) {
+        self.get().create_user(w_last_login, w_nick, w_real_name, w_last_ip, w_is_connected, w_op, w_thread_id)
+    }
+
+    fn edit_user(&mut self, w_last_login: &i64, w_nick: &str, w_last_ip: &str,
+                 w_is_connected: &bool, w_thread_id: &i32) -> Result<(), Error> {
+        self.get().edit_user(w_last_login, w_nick, w_last_ip, w_is_connected, w_thread_id)
+    }
+
+    fn set_connected(&mut self, user: User, w_is_connected: &bool) {
+        self.get().set_connected(user, w_is_connected)
+    }
+
+    fn set_nick(&mut self, user: User, w_nick: &str) {
+        self.get().set_nick(user, w_nick)
+    }
+
+    fn set_real_name(&mut self, user: User, w_real_name: &str) {
+        self.get().set_real_name(user, w_real_name)
+    }
+
+    fn clean_database(&mut self) {
+        self.get().clean_database()
+    }
+
+    fn create_whowas(&mut self, user: User) {
+        self.get().create_whowas(user)
+    }
+
+    fn get_whowas(&mut self, w_nick: &str, w_count: i64) -> Result<Vec<Whowas>, Error> {
+        self.get().get_whowas(w_nick, w_count)
+    }
+
+    fn get_user_from_certfp(&mut self, w_fingerprint: &str) -> Result<User, Error> {
+        self.get().get_user_from_certfp(w_fingerprint)
+    }
+
+    fn get_user_certfps(&mut self, user: User) -> Vec<Certfp> {
+        self.get().get_user_certfps(user)
+    }
+
+    fn create_certfp(&mut self, user: User, w_fingerprint: &str) {
+        self.get().create_certfp(user, w_fingerprint)
+    }
+
+    fn delete_certfp(&mut self, user: User, w_fingerprint: &str) -> bool {
+        self.get().delete_certfp(user, w_fingerprint)
+    }
+
+    fn get_all_bans(&mut self) -> Vec<Ban> {
+        self.get().get_all_bans()
+    }
+
+    fn get_all_channels(&mut self) -> Result<Vec<Channel>, Error> {
+        self.get().get_all_channels()
+    }
+
+    fn get_setting(&mut self, w_key: &str) -> Result<Setting, Error> {
+        self.get().get_setting(w_key)
+    }
+
+    fn get_all_listeners(&mut self) -> Vec<Listener> {
+        self.get().get_all_listeners()
+    }
+}
+
+/// Public function running `query` on the blocking pool of the runtime, with a connection from the database pool
+/// (only taken if `query` uses it, see `LazyConnection`),
+///
+/// Returns `JoinError` if `query` panicked, so the caller can clean up instead of panicking too.
+///
+/// Example:
+/// ```rust
+/// let user = db(|connection| connection.get_user_from_nick("johndoe")).await.unwrap();
+/// ```
+pub async fn db<T, F>(query: F) -> Result<T, JoinError>
+where
+    F: FnOnce(&mut dyn Storage) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_blocking(move || query(&mut LazyConnection::new())).await
+}
+
+/// Insertable private struct linked to database using Diesel.
+#[derive(Insertable)]
+#[diesel(table_name = users)]
+struct NewUser<'a> {
+    pub last_login: &'a i64,
+    pub nick: &'a str,
+    pub real_name: &'a str,
+    pub last_ip: &'a str,
+    pub is_connected: &'a bool,
+    pub op: &'a bool,
+    pub thread_id: &'a i32,
+}
+
+/// Insertable private struct linked to database using Diesel.
+#[derive(Insertable)]
+#[diesel(table_name = whowas)]
+struct NewWhowas<'a> {
+    pub nick: &'a str,
+    pub real_name: &'a str,
+    pub ip: &'a str,
+    pub signoff_time: &'a i64,
+}
+
+/// Insertable private struct linked to database using Diesel.
+#[derive(Insertable)]
+#[diesel(table_name = certfps)]
+struct NewCertfp<'a> {
+    pub id_user: &'a i32,
+    pub fingerprint: &'a str,
+}
+
+impl Storage for DbConnection {
+    fn get_user_from_nick(&mut self, w_nick: &str) -> Result<User, Error> {
+        use crate::rirc_schema::users::dsl::*;
+
+        let mut user = users
+            .limit(1)
+            .filter(nick.eq(w_nick))
+            .load::<User>(self)
+            .expect("Error loading users")
+            .into_iter();
+
+        if user.len() > 0 {
+            Ok(user.nth(0).unwrap())
+        } else {
+            Err(NoResultInDatabase)
+        }
+    }
+
+    fn create_user(&mut self, w_last_login: &i64, w_nick: &str, w_real_name: &str,
+                   w_last_ip: &str, w_is_connected: &bool, w_op: &bool, w_thread_id: &i32) {
+
+        let new_user = NewUser {
+            last_login: w_last_login,
+            nick: w_nick,
+            real_name: w_real_name,
+            last_ip: w_last_ip,
+            is_connected: w_is_connected,
+            op: w_op,
+            thread_id: w_thread_id
+        };
+
+        diesel::insert_into(users::table)
+            .values(&new_user)
+            .execute(self)
+            .expect("Error saving new user");
+    }
+
+    fn edit_user(&mut self, w_last_login: &i64, w_nick: &str, w_last_ip: &str,
+                 w_is_connected: &bool, w_thread_id: &i32) -> Result<(), Error> {
+        use crate::rirc_schema::users::dsl::*;
+        use crate::rirc_schema::users;
+
+        if self.get_user_from_nick(w_nick).is_err() {
+            return Err(NoResultInDatabase);
+        }
+
+        diesel::update(users::table)
+            .filter(nick.eq(w_nick))
+            .set((
+                last_login.eq(w_last_login),
+                last_ip.eq(w_last_ip),
+                is_connected.eq(w_is_connected),
+                thread_id.eq(w_thread_id),
+            ))
+            .execute(self)
+            .expect("Error editing user");
+
+        Ok(())
+    }
+
+    fn set_connected(&mut self, user: User, w_is_connected: &bool) {
+        use crate::rirc_schema::users::dsl::*;
+        use crate::rirc_schema::users;
+
+        diesel::update(users::table)
+            .filter(id.eq(user.id))
+            .set(is_connected.eq(w_is_connected))
+            .execute(self)
+            .expect("Error editing user");
+
+        // if we want to declare our user as logged off
+        if ! w_is_connected {
+            diesel::update(users::table)
+                .filter(thread_id.eq(user.thread_id))
+                .set(thread_id.eq(-1))
+                .execute(self)
+                .expect("Error editing user");
+        }
+    }
+
+    fn set_nick(&mut self, user: User, w_nick: &str) {
+        use crate::rirc_schema::users::dsl::*;
+        use crate::rirc_schema::users;
+
+        diesel::update(users::table)
+            .filter(id.eq(user.id))
+            .set(nick.eq(w_nick))
+            .execute(self)
+            .expect("Error editing user");
+    }
+
+    fn set_real_name(&mut self, user: User, w_real_name: &str) {
+        use crate::rirc_schema::users::dsl::*;
+        use crate::rirc_schema::users;
+
+        diesel::update(users::table)
+            .filter(id.eq(user.id))
+            .set(real_name.eq(w_real_name))
+            .execute(self)
+            .expect("Error editing user");
+    }
+
+    fn clean_database(&mut self) {
+        use crate::rirc_schema::users::dsl::*;
+        use crate::rirc_schema::users;
+
+        diesel::update(users::table)
+            .set((is_connected.eq(false), thread_id.eq(-1)))
+            .execute(self)
+            .expect("Error editing user");
+    }
+
+    fn create_whowas(&mut self, user: User) {
+        use crate::rirc_schema::whowas;
+
+        let new_whowas = NewWhowas {
+            nick: user.nick.as_str(),
+            real_name: user.real_name.as_str(),
+            ip: user.last_ip.as_str(),
+            signoff_time: &get_current_epoch(),
+        };
+
+        diesel::insert_into(whowas::table)
+            .values(&new_whowas)
+            .execute(self)
+            .expect("Error saving whowas entry");
+    }
+
+    fn get_whowas(&mut self, w_nick: &str, w_count: i64) -> Result<Vec<Whowas>, Error> {
+        use crate::rirc_schema::whowas::dsl::*;
+
+        let mut query = whowas
+            .filter(nick.eq(w_nick))
+            .order((signoff_time.desc(), id.desc()))
+            .into_boxed();
+
+        if w_count > 0 {
+            query = query.limit(w_count);
+        }
+
+        let history = query
+            .load::<Whowas>(self)
+            .expect("Error loading whowas");
+
+        if ! history.is_empty() {
+            Ok(history)
+        } else {
+            Err(NoResultInDatabase)
+        }
+    }
+
+    fn get_user_from_certfp(&mut self, w_fingerprint: &str) -> Result<User, Error> {
+        use crate::rirc_schema::certfps::dsl::*;
+        use crate::rirc_schema::users;
+
+        let mut user = certfps
+            .inner_join(users::table.on(users::id.eq(id_user)))
+            .filter(fingerprint.eq(w_fingerprint))
+            .select(users::all_columns)
+            .limit(1)
+            .load::<User>(self)
+            .expect("Error loading certfps")
+            .into_iter();
+
+        if user.len() > 0 {
+            Ok(user.nth(0).unwrap())
+        } else {
+            Err(NoResultInDatabase)
+        }
+    }
+
+    fn get_user_certfps(&mut self, user: User) -> Vec<Certfp> {
+        use crate::rirc_schema::certfps::dsl::*;
+
+        certfps
+            .filter(id_user.eq(user.id))
+            .order(id.asc())
+            .load::<Certfp>(self)
+            .expect("Error loading certfps")
+    }
+
+    fn create_certfp(&mut self, user: User, w_fingerprint: &str) {
+        use crate::rirc_schema::certfps;
+
+        let new_certfp = NewCertfp {
+            id_user: &user.id,
+            fingerprint: w_fingerprint,
+        };
+
+        diesel::insert_into(certfps::table)
+            .values(&new_certfp)
+            .execute(self)
+            .expect("Error saving new certfp");
+    }
+
+    fn delete_certfp(&mut self, user: User, w_fingerprint: &str) -> bool {
+        use crate::rirc_schema::certfps;
+        use crate::rirc_schema::certfps::dsl::*;
+
+        diesel::delete(certfps::table)
+            .filter(id_user.eq(user.id))
+            .filter(fingerprint.eq(w_fingerprint))
+            .execute(self)
+            .expect("Error removing certfp") > 0
+    }
+
+    fn get_all_bans(&mut self) -> Vec<Ban> {
+        use crate::rirc_schema::bans::dsl::*;
+
+        bans
+            .load::<Ban>(self)
+            .expect("Error loading bans")
+    }
+
+    fn get_all_channels(&mut self) -> Result<Vec<Channel>, Error> {
+        use crate::rirc_schema::channels::dsl::*;
+
+        // No channel is not an error, server can run without any
+        Ok(channels
+            .load::<Channel>(self)
+            .expect("Error loading channels"))
+    }
+
+    fn get_setting(&mut self, w_key: &str) -> Result<Setting, Error> {
+        use crate::rirc_schema::settings::dsl::*;
+
+        let mut setting = settings
+            .limit(1)
+            .filter(key.eq(w_key))
+            .load::<Setting>(self)
+            .expect("Error loading settings")
+            .into_iter();
+
+        if setting.len() > 0 {
+            Ok(setting.nth(0).unwrap())
+        } else {
+            // Rows override the configuration file
+            config().setting(w_key)
+                .map(|value| Setting { id: 0, key: w_key.to_string(), content: value })
+                .ok_or(NoResultInDatabase)
+        }
+    }
+
+    fn get_all_listeners(&mut self) -> Vec<Listener> {
+        use crate::rirc_schema::listeners::dsl::*;
+
+        let rows = listeners
+            .order(id.asc())
+            .load::<Listener>(self)
+            .expect("Error loading listeners");
+
+        if rows.is_empty() {
+            return config().listeners();
+        }
+
+        rows
+    }
+}
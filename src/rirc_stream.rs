@@ -0,0 +1,413 @@
+//! # RustyRC Stream
+//!
+//! File containing `ClientStream`, the stream clients are connected through, either plain TCP, TLS, a Unix domain socket
+//! or a WebSocket (over plain TCP or TLS).
+//!
+//! Once accepted (and handshakes are done), a `ClientStream` is split into a `LineReader`, read by the connection handler,
+//! and a `LineWriter`, owned by the task sending lines to the client, so both can wait on the network at once.
+//!
+//! WebSocket clients send and receive one IRC line per frame (IRCv3 `text.ircv3.net` and `binary.ircv3.net` subprotocols),
+//! frames are turned into lines ending with `\n`, so the rest of the server handles them like any other client.
+//!
+//! TLS clients can be asked for a certificate, it's never verified against any authority:
+//! only its SHA-256 fingerprint (CertFP) is used, to recognize clients.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader as StdBufReader, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::{SplitSink, SplitStream};
+use rustls::{DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+
+/// Longest line a client may send, line ending included (RFC 1459).
+pub const MAX_LINE: usize = 512;
+
+/// Longest message tags (`@...` prefix) a client may send in front of a line, trailing space included (IRCv3).
+pub const MAX_TAGS: usize = 8191;
+
+/// Time clients are given to complete TLS and WebSocket handshakes.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// WebSocket subprotocol for clients exchanging UTF-8 text frames.
+const WEBSOCKET_TEXT: &str = "text.ircv3.net";
+
+/// WebSocket subprotocol for clients exchanging binary frames.
+const WEBSOCKET_BINARY: &str = "binary.ircv3.net";
+
+/// Any stream bytes can be read from and written to (TCP, TLS, Unix domain socket).
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// What lines go through.
+enum Transport {
+    Bytes(Box<dyn Io>),
+    WebSocket(Box<WebSocketStream<Box<dyn Io>>>, bool), // `true` if client chose `binary.ircv3.net`
+}
+
+/// Stream a client is connected through, until it's `split()`.
+pub struct ClientStream {
+    transport: Transport,
+    pub addr: SocketAddr,
+    pub certfp: Option<String>, // SHA-256 fingerprint (lowercase hex) of the certificate client sent, if any
+}
+
+impl ClientStream {
+    /// Wraps a freshly accepted plain `TcpStream`, IPv4 clients of a dual-stack (`::`) listener are seen as IPv4.
+    pub fn plain(stream: TcpStream, addr: SocketAddr) -> ClientStream {
+        ClientStream {
+            transport: Transport::Bytes(Box::new(stream)),
+            addr: SocketAddr::new(addr.ip().to_canonical(), addr.port()),
+            certfp: Option::None,
+        }
+    }
+
+    /// Runs the TLS handshake on a freshly accepted `TcpStream`, using `config`.
+    pub async fn tls(stream: TcpStream, addr: SocketAddr, config: Arc<ServerConfig>) -> io::Result<ClientStream> {
+        let stream = timeout(HANDSHAKE_TIMEOUT, TlsAcceptor::from(config).accept(stream)).await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))??;
+
+        let certfp = stream.get_ref().1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| fingerprint(certificate));
+
+        Ok(ClientStream {
+            transport: Transport::Bytes(Box::new(stream)),
+            addr: SocketAddr::new(addr.ip().to_canonical(), addr.port()),
+            certfp,
+        })
+    }
+
+    /// Wraps a freshly accepted `UnixStream`, local clients (bots) are seen as connecting from `127.0.0.1:0`.
+    pub fn unix(stream: UnixStream) -> ClientStream {
+        ClientStream {
+            transport: Transport::Bytes(Box::new(stream)),
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            certfp: Option::None,
+        }
+    }
+
+    /// Runs the WebSocket handshake on this stream (plain or TLS),
+    ///
+    /// Clients have to send an `Origin` header from `origins`, unless it's empty.
+    ///
+    /// Example:
+    /// ```rust
+    /// let stream = ClientStream::plain(stream, addr).websocket(&["https://chat.example.com".to_string()]).await.unwrap();
+    /// ```
+    pub async fn websocket(self, origins: &[String]) -> io::Result<ClientStream> {
+        let stream = match self.transport {
+            Transport::Bytes(stream) => stream,
+            Transport::WebSocket(_, _) => return Err(io::Error::other("Already a WebSocket")),
+        };
+
+        let mut binary = false;
+        let callback = Handshake { origins, binary: &mut binary };
+
+        // A frame is a line, it can't be longer than the longest line
+        let config = WebSocketConfig::default()
+            .max_message_size(Some(MAX_TAGS + MAX_LINE))
+            .max_frame_size(Some(MAX_TAGS + MAX_LINE));
+
+        let socket = timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config))).await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "WebSocket handshake timed out"))?
+            .map_err(io::Error::other)?;
+
+        Ok(ClientStream {
+            transport: Transport::WebSocket(Box::new(socket), binary),
+            ..self
+        })
+    }
+
+    /// Splits stream into a `LineReader` and a `LineWriter`.
+    pub fn split(self) -> (LineReader, LineWriter) {
+        match self.transport {
+            Transport::Bytes(stream) => {
+                let (reader, writer) = tokio::io::split(stream);
+
+                (LineReader::Bytes(BufReader::new(reader)), LineWriter::Bytes(writer))
+            }
+            Transport::WebSocket(socket, binary) => {
+                let (writer, reader) = (*socket).split();
+
+                (LineReader::WebSocket(reader), LineWriter::WebSocket(writer, binary))
+            }
+        }
+    }
+}
+
+/// Checks the WebSocket handshake request of a client, see `ClientStream::websocket()`.
+struct Handshake<'a> {
+    origins: &'a [String], // `Origin` headers allowed, any if empty
+    binary: &'a mut bool, // set to `true` if client chose `binary.ircv3.net`
+}
+
+impl Callback for Handshake<'_> {
+    fn on_request(self, request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        let origin = request.headers().get("Origin").and_then(|origin| origin.to_str().ok()).unwrap_or("");
+
+        if ! self.origins.is_empty() && ! self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            let mut error = ErrorResponse::new(Some("Origin not allowed".to_string()));
+            *error.status_mut() = StatusCode::FORBIDDEN;
+            return Err(error);
+        }
+
+        // First IRC subprotocol offered by client, clients offering none are sent text frames
+        let protocol = request.headers().get_all("Sec-WebSocket-Protocol").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .find(|protocol| *protocol == WEBSOCKET_TEXT || *protocol == WEBSOCKET_BINARY);
+
+        if let Some(protocol) = protocol {
+            *self.binary = protocol == WEBSOCKET_BINARY;
+            response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(if *self.binary { WEBSOCKET_BINARY } else { WEBSOCKET_TEXT }));
+        }
+
+        Ok(response)
+    }
+}
+
+/// Reading half of a `ClientStream`.
+pub enum LineReader {
+    Bytes(BufReader<ReadHalf<Box<dyn Io>>>),
+    WebSocket(SplitStream<WebSocketStream<Box<dyn Io>>>),
+}
+
+impl LineReader {
+    /// Reads until a `\n` is found and appends it to `buffer`, returns 0 once connection is closed,
+    ///
+    /// Lines longer than `MAX_LINE` (plus `MAX_TAGS` if they start with tags), and WebSocket frames holding more than a line
+    /// (CR, LF or NUL before their line ending), are an `InvalidData` error, client has to be disconnected.
+    ///
+    /// Can be cancelled (e.g. timed out): whatever was read stays in `buffer`.
+    pub async fn read_line(&mut self, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        let socket = match self {
+            LineReader::Bytes(reader) => return read_until_limit(reader, buffer).await,
+            LineReader::WebSocket(socket) => socket,
+        };
+
+        loop {
+            let line = match socket.next().await {
+                Some(Ok(Message::Text(text))) => text.as_bytes().to_vec(),
+                Some(Ok(Message::Binary(data))) => data.to_vec(),
+                Some(Ok(Message::Close(_))) | Option::None => return Ok(0),
+                // Pings are answered by tungstenite
+                Some(Ok(_)) => continue,
+                Some(Err(WsError::Capacity(_))) => return Err(line_too_long()),
+                Some(Err(error)) => return Err(io::Error::other(error)),
+            };
+
+            // Each frame is a line, with or without its line ending
+            let end = line.iter().rposition(|byte| *byte != b'\r' && *byte != b'\n').map_or(0, |end| end + 1);
+
+            // A frame can't smuggle more lines in
+            if line[..end].iter().any(|byte| [b'\r', b'\n', b'\0'].contains(byte)) {
+                return Err(invalid_line());
+            }
+
+            buffer.extend_from_slice(&line[..end]);
+            buffer.push(b'\n');
+
+            if buffer.len() > max_line_length(buffer) {
+                return Err(line_too_long());
+            }
+
+            return Ok(end + 1);
+        }
+    }
+}
+
+/// Same as `read_until(b'\n', buffer)`, but fails with `InvalidData` as soon as line in `buffer` is too long.
+async fn read_until_limit(reader: &mut BufReader<ReadHalf<Box<dyn Io>>>, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let mut read = 0;
+
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(read);
+        }
+
+        let (used, done) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(end) => (end + 1, true),
+            Option::None => (available.len(), false),
+        };
+
+        buffer.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        read += used;
+
+        if buffer.len() > max_line_length(buffer) {
+            return Err(line_too_long());
+        }
+
+        if done {
+            return Ok(read);
+        }
+    }
+}
+
+/// Returns longest length allowed for `line`, depending on whether it starts with tags.
+fn max_line_length(line: &[u8]) -> usize {
+    if line.first() == Some(&b'@') { MAX_TAGS + MAX_LINE } else { MAX_LINE }
+}
+
+/// Error returned by `LineReader::read_line()` for lines too long.
+fn line_too_long() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "Line too long")
+}
+
+fn invalid_line() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "Invalid line")
+}
+
+/// Writing half of a `ClientStream`.
+pub enum LineWriter {
+    Bytes(WriteHalf<Box<dyn Io>>),
+    WebSocket(SplitSink<WebSocketStream<Box<dyn Io>>, Message>, bool), // `true` if client chose `binary.ircv3.net`
+}
+
+impl LineWriter {
+    /// Sends `content` (one or more lines separated by `\n`), each line ending with `\n`, or in its own WebSocket frame.
+    pub async fn write_lines(&mut self, content: &str) -> io::Result<()> {
+        match self {
+            LineWriter::Bytes(writer) => writer.write_all((content.to_string() + "\n").as_bytes()).await,
+            LineWriter::WebSocket(socket, binary) => {
+                for line in content.split('\n').map(|line| line.trim_end_matches('\r')).filter(|line| ! line.is_empty()) {
+                    let message = if *binary { Message::binary(line.as_bytes().to_vec()) } else { Message::text(line) };
+
+                    socket.feed(message).await.map_err(io::Error::other)?;
+                }
+
+                socket.flush().await.map_err(io::Error::other)
+            }
+        }
+    }
+
+    /// Closes connection, WebSocket clients are sent a Close frame and TLS connections a close_notify alert first.
+    pub async fn close(&mut self) -> io::Result<()> {
+        match self {
+            LineWriter::Bytes(writer) => writer.shutdown().await,
+            LineWriter::WebSocket(socket, _) => socket.close().await.map_err(io::Error::other),
+        }
+    }
+}
+
+/// TLS configuration shared by TLS listeners, it can be replaced while they run (see `rirc_rehash`),
+/// connections already open keep the configuration they were accepted with.
+#[derive(Clone)]
+pub struct TlsConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl TlsConfig {
+    /// Create a `TlsConfig` from `config`, see `load_tls_config()`.
+    pub fn new(config: Arc<ServerConfig>) -> TlsConfig {
+        TlsConfig(Arc::new(RwLock::new(config)))
+    }
+
+    /// Returns configuration to accept new connections with.
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Makes every listener sharing this `TlsConfig` accept new connections with `config`.
+    pub fn replace(&self, config: Arc<ServerConfig>) {
+        *self.0.write().unwrap() = config;
+    }
+}
+
+/// Public function building a TLS `ServerConfig` from PEM certificate chain and private key files,
+///
+/// If `request_client_cert` is `true`, clients are asked for a certificate (see `ClientStream::certfp`),
+/// connections without one are still accepted.
+///
+/// Example:
+/// ```rust
+/// let config = load_tls_config("/etc/rustyrc/cert.pem", "/etc/rustyrc/key.pem", true).unwrap();
+/// ```
+pub fn load_tls_config(cert_path: &str, key_path: &str, request_client_cert: bool) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_reader_iter(StdBufReader::new(File::open(cert_path).map_err(|error| format!("{}: {}", cert_path, error))?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| format!("{}: {}", cert_path, error))?;
+
+    if certs.is_empty() {
+        return Err(format!("{}: no certificate found", cert_path));
+    }
+
+    let key = PrivateKeyDer::from_pem_reader(StdBufReader::new(File::open(key_path).map_err(|error| format!("{}: {}", key_path, error))?))
+        .map_err(|error| format!("{}: {}", key_path, error))?;
+
+    let builder = ServerConfig::builder();
+    let builder = if request_client_cert {
+        builder.with_client_cert_verifier(Arc::new(AnyClientCert {
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }))
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|error| format!("{}: {}", cert_path, error))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Returns SHA-256 fingerprint of `certificate`, as lowercase hex.
+pub fn fingerprint(certificate: &CertificateDer) -> String {
+    Sha256::digest(certificate.as_ref())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// `ClientCertVerifier` asking clients for a certificate without requiring one,
+/// any certificate is accepted as long as client proves it owns its key.
+#[derive(Debug)]
+struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _now: UnixTime) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}